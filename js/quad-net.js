@@ -9,9 +9,14 @@ register_plugin = function (importObject) {
 
     importObject.env.http_make_request = http_make_request;
     importObject.env.http_try_recv = http_try_recv;
+    importObject.env.http_try_recv_chunk = http_try_recv_chunk;
+    importObject.env.http_cancel = http_cancel;
+    importObject.env.http_progress = http_progress;
+    importObject.env.http_forget = http_forget;
+    importObject.env.http_random = http_random;
 }
 
-miniquad_add_plugin({register_plugin, on_init, version: 1, name: "quad_net"});
+miniquad_add_plugin({register_plugin, on_init, version: 2, name: "quad_net"});
 
 var quad_socket;
 var received_buffer = [];
@@ -21,6 +26,12 @@ const PackedReceived = 1;
 const SocketError = 2;
 const Closed = 3;
 
+// Queue a message for rust and wake up the task awaiting it, if any
+function ws_deliver(message) {
+    received_buffer.push(message);
+    wasm_exports.quad_net_ws_wake();
+}
+
 function ws_connect(a) {
     received_buffer = [];
 
@@ -30,7 +41,7 @@ function ws_connect(a) {
     quad_socket = new WebSocket(addr);
     quad_socket.binaryType = 'arraybuffer';
     quad_socket.onopen = function () {
-        received_buffer.push({
+        ws_deliver({
             "type": Connected,
         });
     };
@@ -40,7 +51,7 @@ function ws_connect(a) {
             console.error("Received string data: ", msg.data);
         } else {
             const buffer = new Uint8Array(msg.data);
-            received_buffer.push({
+            ws_deliver({
                 "type": PackedReceived,
                 "data": buffer
             });
@@ -49,14 +60,14 @@ function ws_connect(a) {
 
     quad_socket.onerror = function (error) {
         console.error("Websocket error:", error);
-        received_buffer.push({
+        ws_deliver({
             "type": SocketError,
             "data": JSON.stringify(error)
         });
     };
 
     quad_socket.onclose = function () {
-        received_buffer.push({
+        ws_deliver({
             "type": Closed,
         });
     };
@@ -81,7 +92,7 @@ function ws_send(data) {
 
         const error_message = error.message;
 
-        received_buffer.push({
+        ws_deliver({
             "type": SocketError,
             "data": JSON.stringify(error_message)
         });
@@ -98,55 +109,382 @@ function ws_try_recv() {
 
 let uid = 0;
 const ongoing_requests = {};
+const ongoing_transfers = {};
+const requests_progress = {};
+const stream_chunks = {};
+const stream_waiters = {};
+
+const HttpNetworkError = 0;
+const HttpAborted = 1;
+const HttpTimeout = 2;
+const HttpTooLarge = 3;
+
+// Chunks of a streamed response waiting for rust before the download pauses
+const HttpStreamBufferedChunks = 16;
+
+// Whether a body of this many bytes is over the limit set by rust, if any
+function http_too_large(options, bytes) {
+    return options.max_response_bytes !== undefined && bytes > options.max_response_bytes;
+}
+
+// Randomness for rust, which has no source of its own on web
+function http_random() {
+    return Math.random();
+}
+
+// Hand the outcome of a request to rust and wake up the task awaiting it, if any
+function http_deliver(cid, result) {
+    ongoing_requests[cid] = result;
+    wasm_exports.quad_net_http_wake(cid);
+}
 
 function http_try_recv(cid) {
-    if (ongoing_requests[cid] !== undefined && ongoing_requests[cid] != null) {
+    if (ongoing_requests[cid] !== undefined) {
         var data = ongoing_requests[cid];
-        ongoing_requests[cid] = null;
+        delete ongoing_requests[cid];
         return js_object(data);
     }
     return -1;
 }
 
-function http_make_request(scheme, url, body, headers) {
-    const cid = uid;
-
-    uid += 1;
-
-    let scheme_string;
-    if (scheme === 0) {
-        scheme_string = 'POST';
+function http_try_recv_chunk(cid) {
+    const chunks = stream_chunks[cid];
+    if (chunks !== undefined && chunks.length !== 0) {
+        const chunk = chunks.shift();
+        const waiter = stream_waiters[cid];
+        if (waiter !== undefined) {
+            delete stream_waiters[cid];
+            waiter();
+        }
+        return js_object(chunk);
     }
-    if (scheme === 1) {
-        scheme_string = 'PUT';
+    return -1;
+}
+
+function http_progress(cid) {
+    if (requests_progress[cid] !== undefined) {
+        return js_object(requests_progress[cid]);
     }
-    if (scheme === 2) {
-        scheme_string = 'GET';
+    return -1;
+}
+
+function http_cancel(cid) {
+    const xhr = ongoing_transfers[cid];
+    if (xhr !== undefined) {
+        xhr.abort();
     }
-    if (scheme === 3) {
-        scheme_string = 'DELETE';
+}
+
+// The rust side is gone: stop the transfer and drop whatever it produced
+function http_forget(cid) {
+    const transfer = ongoing_transfers[cid];
+    // Removed first, so the abort handlers see the request is forgotten
+    delete ongoing_transfers[cid];
+    delete requests_progress[cid];
+    delete stream_chunks[cid];
+    delete stream_waiters[cid];
+    if (transfer !== undefined) {
+        transfer.onabort = null;
+        transfer.abort();
     }
+    delete ongoing_requests[cid];
+}
+
+function http_make_request(method, url, body, headers, options) {
+    const cid = uid;
+
+    uid += 1;
+
+    var method_string = consume_js_object(method);
     var url_string = consume_js_object(url);
-    var body_string = consume_js_object(body);
+    var body_data = consume_js_object(body);
     var headers_obj = consume_js_object(headers);
+    var options_obj = consume_js_object(options);
+
+    requests_progress[cid] = {
+        "download": {
+            "loaded": 0
+        },
+        "upload": {
+            "loaded": 0,
+            "total": body_data.length
+        }
+    };
+
+    // XMLHttpRequest can only hand over the body once it is complete
+    const start = options_obj.stream ? http_start_stream : http_start_xhr;
+
+    if (options_obj.delay_ms > 0) {
+        // A retry, waiting for its backoff
+        const timer = setTimeout(function () {
+            start(cid, method_string, url_string, body_data, headers_obj, options_obj);
+        }, options_obj.delay_ms);
+        ongoing_transfers[cid] = {
+            abort: function () {
+                clearTimeout(timer);
+                delete ongoing_transfers[cid];
+                http_deliver(cid, {
+                    "error": HttpAborted
+                });
+            }
+        };
+    } else {
+        start(cid, method_string, url_string, body_data, headers_obj, options_obj);
+    }
+
+    return cid;
+}
+
+function http_start_xhr(cid, method_string, url_string, body_data, headers_obj, options_obj) {
     var xhr = new XMLHttpRequest();
-    xhr.open(scheme_string, url_string, true);
+    xhr.open(method_string, url_string, true);
     xhr.responseType = 'arraybuffer';
+    // 0 means no timeout, same as XMLHttpRequest's own default
+    xhr.timeout = options_obj.timeout_ms;
+
+    // XMLHttpRequest has only the overall timeout, connect and read timeouts
+    // are emulated with a timer that aborts the request when it runs out
+    var timed_out = false;
+    var watchdog = null;
+    function arm_watchdog(timeout_ms) {
+        clearTimeout(watchdog);
+        if (timeout_ms > 0) {
+            watchdog = setTimeout(function () {
+                timed_out = true;
+                xhr.abort();
+            }, timeout_ms);
+        }
+    }
+    arm_watchdog(options_obj.connect_timeout_ms);
+    var too_large = false;
+    function abort_too_large() {
+        too_large = true;
+        xhr.abort();
+    }
+    xhr.onreadystatechange = function () {
+        if (this.readyState === XMLHttpRequest.HEADERS_RECEIVED) {
+            arm_watchdog(options_obj.read_timeout_ms);
+            // No need to download what is announced too large
+            const length = this.getResponseHeader("Content-Length");
+            if (length !== null && http_too_large(options_obj, parseInt(length))) {
+                abort_too_large();
+            }
+        }
+    };
+    function progress_handler(direction) {
+        return function (e) {
+            var progress = {
+                "loaded": e.loaded
+            };
+            if (e.lengthComputable) {
+                progress["total"] = e.total;
+            }
+            requests_progress[cid][direction] = progress;
+        };
+    }
+    const on_download_progress = progress_handler("download");
+    xhr.onprogress = function (e) {
+        arm_watchdog(options_obj.read_timeout_ms);
+        on_download_progress(e);
+        if (http_too_large(options_obj, e.loaded)) {
+            abort_too_large();
+        }
+    };
+    // Upload listeners make the browser send a CORS preflight,
+    // so only pay for it when there is something to upload
+    if (body_data.length > 0) {
+        xhr.upload.onprogress = progress_handler("upload");
+    }
+    xhr.onloadend = function (e) {
+        clearTimeout(watchdog);
+        delete ongoing_transfers[cid];
+    };
     for (const header in headers_obj) {
         xhr.setRequestHeader(header, headers_obj[header]);
     }
     xhr.onload = function (e) {
-        if (this.status === 200) {
-            ongoing_requests[cid] = new Uint8Array(this.response);
-        }
+        // XMLHttpRequest does not say whether it was redirected, unlike fetch
+        const requested_url = new URL(url_string, document.baseURI);
+        requested_url.hash = "";
+        http_deliver(cid, {
+            "status": this.status,
+            "url": this.responseURL,
+            "redirected": this.responseURL !== requested_url.href ? 1 : 0,
+            "headers": this.getAllResponseHeaders(),
+            "body": new Uint8Array(this.response)
+        });
     }
     xhr.onerror = function (e) {
-        // todo: let rust know and put Error to ongoing requests
+        // Network failures and CORS rejections both end up here, with status 0
         console.error("Failed to make a request");
         console.error(e);
+        http_deliver(cid, {
+            "error": HttpNetworkError
+        });
+    };
+    xhr.onabort = function (e) {
+        var error = HttpAborted;
+        if (timed_out) {
+            error = HttpTimeout;
+        } else if (too_large) {
+            error = HttpTooLarge;
+        }
+        http_deliver(cid, {
+            "error": error
+        });
+    };
+    xhr.ontimeout = function (e) {
+        http_deliver(cid, {
+            "error": HttpTimeout
+        });
     };
 
-    xhr.send(body_string);
+    xhr.send(body_data);
+    ongoing_transfers[cid] = xhr;
+}
 
-    return cid;
+function http_start_stream(cid, method, url, body, headers, options) {
+    const controller = new AbortController();
+    const chunks = [];
+    stream_chunks[cid] = chunks;
+    ongoing_transfers[cid] = controller;
+
+    // Same timeouts as with XMLHttpRequest, fetch only knows how to abort
+    var timed_out = false;
+    function time_out() {
+        timed_out = true;
+        controller.abort();
+    }
+    var deadline = options.timeout_ms > 0 ? setTimeout(time_out, options.timeout_ms) : null;
+    var watchdog = null;
+    function arm_watchdog(timeout_ms) {
+        clearTimeout(watchdog);
+        if (timeout_ms > 0) {
+            watchdog = setTimeout(time_out, timeout_ms);
+        }
+    }
+    function finish() {
+        clearTimeout(deadline);
+        clearTimeout(watchdog);
+        delete ongoing_transfers[cid];
+    }
+    arm_watchdog(options.connect_timeout_ms);
+
+    var response_delivered = false;
+    function fail(e) {
+        finish();
+        // Forgotten by rust, nobody is going to pick the error up
+        if (stream_chunks[cid] === undefined) {
+            return;
+        }
+
+        var error = HttpNetworkError;
+        if (e.name === "AbortError") {
+            error = HttpAborted;
+            if (timed_out) {
+                error = HttpTimeout;
+            }
+        } else if (e.name === "TooLarge") {
+            error = HttpTooLarge;
+        } else {
+            console.error("Failed to make a request");
+            console.error(e);
+        }
+        if (response_delivered) {
+            chunks.push({
+                "error": error
+            });
+        } else {
+            http_deliver(cid, {
+                "error": error
+            });
+        }
+    }
+    // Nothing is pending between two reads, so aborting alone would go unnoticed
+    function fail_too_large() {
+        controller.abort();
+        fail({
+            "name": "TooLarge"
+        });
+    }
+
+    fetch(url, {
+        method: method,
+        headers: headers,
+        body: body.length > 0 ? body : undefined,
+        signal: controller.signal
+    }).then(function (response) {
+        if (stream_chunks[cid] === undefined) {
+            return;
+        }
+        arm_watchdog(options.read_timeout_ms);
+
+        // No need to download what is announced too large
+        const length = response.headers.get("Content-Length");
+        if (length !== null && http_too_large(options, parseInt(length))) {
+            fail_too_large();
+            return;
+        }
+
+        var raw_headers = "";
+        response.headers.forEach(function (value, name) {
+            raw_headers += name + ": " + value + "\r\n";
+        });
+        http_deliver(cid, {
+            "status": response.status,
+            "url": response.url,
+            "redirected": response.redirected ? 1 : 0,
+            "headers": raw_headers,
+            "body": new Uint8Array(0)
+        });
+        response_delivered = true;
+
+        var download = {
+            "loaded": 0
+        };
+        if (length !== null) {
+            download["total"] = parseInt(length);
+        }
+        requests_progress[cid]["download"] = download;
+        requests_progress[cid]["upload"]["loaded"] = body.length;
+
+        const reader = response.body.getReader();
+        function pump() {
+            if (stream_chunks[cid] === undefined) {
+                reader.cancel();
+                return;
+            }
+            // Wait for rust to take some chunks, see http_try_recv_chunk.
+            // The server is not the one keeping us waiting, so the read
+            // timeout only starts over once reading resumes
+            if (chunks.length >= HttpStreamBufferedChunks) {
+                clearTimeout(watchdog);
+                stream_waiters[cid] = function () {
+                    arm_watchdog(options.read_timeout_ms);
+                    pump();
+                };
+                return;
+            }
+            reader.read().then(function (result) {
+                if (result.done) {
+                    finish();
+                    chunks.push({
+                        "end": 1
+                    });
+                    return;
+                }
+                arm_watchdog(options.read_timeout_ms);
+                download["loaded"] += result.value.length;
+                if (http_too_large(options, download["loaded"])) {
+                    fail_too_large();
+                    return;
+                }
+                chunks.push({
+                    "data": result.value
+                });
+                pump();
+            }).catch(fail);
+        }
+        pump();
+    }).catch(fail);
 }
@@ -1,15 +1,84 @@
 //! Async http requests.
 
+#[cfg(not(target_arch = "wasm32"))]
+mod cache;
+mod client;
+#[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+mod cookies;
+#[cfg(all(feature = "compression", not(target_arch = "wasm32")))]
+mod encoding;
+mod multipart;
+#[cfg(not(target_arch = "wasm32"))]
+mod pool;
+#[cfg(not(target_arch = "wasm32"))]
+mod proxy_auth;
+mod rate_limit;
+mod retry;
+#[cfg(not(target_arch = "wasm32"))]
+mod tls;
+#[cfg(not(target_arch = "wasm32"))]
+mod watchdog;
+
+pub use client::{HttpClient, HttpClientBuilder};
+pub use multipart::Multipart;
+pub use rate_limit::RateLimit;
+pub use retry::RetryPolicy;
+
 #[cfg(target_arch = "wasm32")]
 use crate::JsObject;
+use std::future::Future;
 use std::io::Read;
+use std::pin::Pin;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use ureq::ResponseExt;
 
-#[derive(Debug, Clone, PartialEq, Copy)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Method {
     Post,
     Put,
     Get,
     Delete,
+    Patch,
+    Head,
+    Options,
+    /// Any other method token, like WebDAV's "PROPFIND".
+    Custom(String),
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Get => "GET",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Head => "HEAD",
+            Method::Options => "OPTIONS",
+            Method::Custom(method) => method,
+        }
+    }
+
+    /// Browsers never send a body with GET and HEAD, so neither does desktop.
+    /// Every other method, custom ones included, sends the body if there is one.
+    pub fn allows_body(&self) -> bool {
+        !matches!(self, Method::Get | Method::Head)
+    }
+
+    /// Sending the request twice has the same effect as sending it once.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            Method::Get | Method::Head | Method::Options | Method::Put | Method::Delete
+        )
+    }
 }
 
 #[derive(Debug)]
@@ -17,6 +86,25 @@ pub enum HttpError {
     IOError,
     #[cfg(not(target_arch = "wasm32"))]
     UreqError(ureq::Error),
+    /// The request never got a response: DNS, connection or TLS failure.
+    /// On web this is also what a CORS rejection looks like, browsers do not
+    /// let the page tell them apart.
+    NetworkError,
+    /// The request was aborted before the response arrived.
+    Aborted,
+    /// One of the timeouts set on `RequestBuilder` ran out.
+    Timeout,
+    /// The server kept redirecting past `RequestBuilder::max_redirects`.
+    TooManyRedirects,
+    /// The response body is larger than `RequestBuilder::max_response_bytes`,
+    /// the transfer was stopped.
+    ResponseTooLarge,
+    /// The server answered with 4xx or 5xx and the request was built with
+    /// `http_status_as_error(true)`.
+    Status(Box<Response>),
+    /// A json body could not be encoded, or a response could not be decoded.
+    #[cfg(feature = "json")]
+    Json(serde_json::Error),
 }
 
 impl std::fmt::Display for HttpError {
@@ -25,11 +113,45 @@ impl std::fmt::Display for HttpError {
             HttpError::IOError => write!(f, "IOError"),
             #[cfg(not(target_arch = "wasm32"))]
             HttpError::UreqError(error) => write!(f, "Ureq error: {}", error),
+            HttpError::NetworkError => write!(f, "Network error"),
+            HttpError::Aborted => write!(f, "Request aborted"),
+            HttpError::Timeout => write!(f, "Request timed out"),
+            HttpError::TooManyRedirects => write!(f, "Too many redirects"),
+            HttpError::ResponseTooLarge => write!(f, "Response body too large"),
+            HttpError::Status(response) => write!(f, "Http status {}", response.status),
+            #[cfg(feature = "json")]
+            HttpError::Json(error) => write!(f, "Json error: {}", error),
         }
     }
 }
+impl HttpError {
+    /// The server could not be reached or the connection broke down.
+    fn is_network_error(&self) -> bool {
+        match self {
+            HttpError::NetworkError => true,
+            #[cfg(not(target_arch = "wasm32"))]
+            HttpError::UreqError(error) => matches!(
+                error,
+                ureq::Error::Io(_) | ureq::Error::HostNotFound | ureq::Error::ConnectionFailed
+            ),
+            _ => false,
+        }
+    }
+}
+
 impl From<std::io::Error> for HttpError {
-    fn from(_error: std::io::Error) -> HttpError {
+    fn from(error: std::io::Error) -> HttpError {
+        if error.kind() == std::io::ErrorKind::TimedOut {
+            return HttpError::Timeout;
+        }
+        // ureq body readers wrap ureq's own errors into io::Error
+        #[cfg(not(target_arch = "wasm32"))]
+        if error
+            .get_ref()
+            .is_some_and(|inner| inner.is::<ureq::Error>())
+        {
+            return ureq::Error::from(error).into();
+        }
         HttpError::IOError
     }
 }
@@ -37,45 +159,270 @@ impl From<std::io::Error> for HttpError {
 #[cfg(not(target_arch = "wasm32"))]
 impl From<ureq::Error> for HttpError {
     fn from(error: ureq::Error) -> HttpError {
-        HttpError::UreqError(error)
+        match error {
+            ureq::Error::Timeout(_) => HttpError::Timeout,
+            ureq::Error::TooManyRedirects => HttpError::TooManyRedirects,
+            ureq::Error::BodyExceedsLimit(_) => HttpError::ResponseTooLarge,
+            ureq::Error::Io(error) if error.kind() == std::io::ErrorKind::TimedOut => {
+                HttpError::Timeout
+            }
+            error => HttpError::UreqError(error),
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+impl From<serde_json::Error> for HttpError {
+    fn from(error: serde_json::Error) -> HttpError {
+        HttpError::Json(error)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<ureq::http::Error> for HttpError {
+    fn from(error: ureq::http::Error) -> HttpError {
+        HttpError::UreqError(error.into())
     }
 }
 
 #[cfg(target_arch = "wasm32")]
 #[link(wasm_import_module = "env")]
 unsafe extern "C" {
-    fn http_make_request(scheme: i32, url: JsObject, body: JsObject, headers: JsObject) -> i32;
+    fn http_make_request(
+        method: JsObject,
+        url: JsObject,
+        body: JsObject,
+        headers: JsObject,
+        options: JsObject,
+    ) -> i32;
     fn http_try_recv(cid: i32) -> JsObject;
+    fn http_try_recv_chunk(cid: i32) -> JsObject;
+    fn http_cancel(cid: i32);
+    fn http_progress(cid: i32) -> JsObject;
+    fn http_forget(cid: i32);
+    fn http_random() -> f64;
+}
+
+/// Random number in `[0, 1)`, different for every game instance: taken from
+/// the OS on desktop and from `Math.random` on web.
+fn random() -> f64 {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        use ring::rand::SecureRandom;
+
+        let mut bytes = [0; 8];
+        ring::rand::SystemRandom::new()
+            .fill(&mut bytes)
+            .expect("Failed to get random bytes");
+        // 53 bits, as much as a f64 can tell apart
+        (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64
+    }
+    #[cfg(target_arch = "wasm32")]
+    unsafe {
+        http_random()
+    }
+}
+
+/// How much of a body went through so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Progress {
+    pub transferred: u64,
+    /// Full size of the body, if the other side told it in advance.
+    pub total: Option<u64>,
+}
+
+/// Piece of a streamed response body, see `RequestResponseType::Stream`.
+#[derive(Debug)]
+pub enum BodyChunk {
+    Data(Vec<u8>),
+    /// The whole body was received, no more chunks will follow.
+    End,
+}
+
+/// Redirects followed when `RequestBuilder::max_redirects` is not set, as ureq does.
+#[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+const DEFAULT_MAX_REDIRECTS: u32 = 10;
+/// Longest wait browsers' `setTimeout` takes, about 24 days. Past it the timer
+/// fires right away, while waiting that long is as good as never.
+#[cfg(target_arch = "wasm32")]
+const MAX_TIMER_MS: u32 = i32::MAX as u32;
+
+/// Size of a single chunk read from a streamed response on desktop.
+#[cfg(not(target_arch = "wasm32"))]
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+/// Chunks of a streamed response allowed to wait for `try_recv_chunk`
+/// before the download pauses.
+#[cfg(not(target_arch = "wasm32"))]
+const STREAM_BUFFERED_CHUNKS: usize = 16;
+
+/// State shared between a `Request` and the thread running it.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Default)]
+struct RequestState {
+    cancelled: AtomicBool,
+    /// The final result, or the abort, was sent to the `Request`.
+    finished: AtomicBool,
+    download: Mutex<Progress>,
+    upload: Mutex<Progress>,
+    /// Task awaiting the `Request`, woken up once the response is in.
+    waker: Mutex<Option<Waker>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl RequestState {
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+
+    /// Claim the sending of the final result, false when it was already
+    /// claimed, by the thread running the request or by `Request::cancel`.
+    fn finish(&self) -> bool {
+        !self.finished.swap(true, Ordering::AcqRel)
+    }
+}
+
+/// Reader over a request or response body that keeps track of the progress
+/// and stops as soon as the request is cancelled.
+#[cfg(not(target_arch = "wasm32"))]
+struct TransferReader<'a, R> {
+    inner: R,
+    state: &'a RequestState,
+    progress: &'a Mutex<Progress>,
+    /// Bytes allowed through, see `RequestBuilder::max_response_bytes`.
+    limit: Option<u64>,
+    /// Longest silence allowed from the server, see `RequestBuilder::read_timeout`.
+    idle_timeout: Option<Duration>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<R: Read> Read for TransferReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.state.is_cancelled() {
+            return Err(std::io::Error::other("Http request cancelled"));
+        }
+        let read = watchdog::idle_timeout(self.idle_timeout, || self.inner.read(buf))?;
+        let mut progress = self.progress.lock().unwrap();
+        progress.transferred += read as u64;
+        if let Some(limit) = self.limit
+            && progress.transferred > limit
+        {
+            return Err(ureq::Error::BodyExceedsLimit(limit).into_io());
+        }
+        Ok(read)
+    }
 }
 
+/// A request in flight.
+///
+/// Dropping it cancels the request.
 #[cfg(not(target_arch = "wasm32"))]
 pub struct Request {
-    rx: std::sync::mpsc::Receiver<Result<ResponsePayload, HttpError>>,
+    rx: std::sync::mpsc::Receiver<Result<Response, HttpError>>,
+    /// Reports the cancel right away, without waiting for the thread running the request.
+    tx: std::sync::mpsc::Sender<Result<Response, HttpError>>,
+    /// Dropped on cancel, to wake up the thread waiting for room in the channel.
+    chunks_rx: Option<std::sync::mpsc::Receiver<Result<BodyChunk, HttpError>>>,
+    state: Arc<RequestState>,
 }
 
 #[cfg(not(target_arch = "wasm32"))]
 impl Request {
-    pub fn try_recv(&mut self) -> Option<Result<ResponsePayload, HttpError>> {
+    pub fn try_recv(&mut self) -> Option<Result<Response, HttpError>> {
         self.rx.try_recv().ok()
     }
+
+    /// Next piece of a body requested with `RequestResponseType::Stream`,
+    /// available once `try_recv` returned the response.
+    pub fn try_recv_chunk(&mut self) -> Option<Result<BodyChunk, HttpError>> {
+        match &self.chunks_rx {
+            Some(chunks_rx) => chunks_rx.try_recv().ok(),
+            None => Some(Err(HttpError::Aborted)),
+        }
+    }
+
+    /// Bytes of the response body received so far.
+    pub fn progress(&self) -> Progress {
+        *self.state.download.lock().unwrap()
+    }
+
+    /// Bytes of the request body sent so far.
+    pub fn upload_progress(&self) -> Progress {
+        *self.state.upload.lock().unwrap()
+    }
+
+    /// Stop the transfer. Unless the outcome is already in, `try_recv` will
+    /// report `HttpError::Aborted`.
+    pub fn cancel(&mut self) {
+        self.state.cancelled.store(true, Ordering::Relaxed);
+        if self.state.finish() {
+            let _ = self.tx.send(Err(HttpError::Aborted));
+        }
+        self.chunks_rx = None;
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Future for Request {
+    type Output = Result<Response, HttpError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Registered before looking, so a response landing in between still wakes the task
+        *self.state.waker.lock().unwrap() = Some(cx.waker().clone());
+        match self.get_mut().try_recv() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for Request {
+    fn drop(&mut self) {
+        self.cancel();
+    }
 }
 
+/// A request in flight.
+///
+/// Dropping it cancels the request.
 #[cfg(target_arch = "wasm32")]
 pub struct Request {
     cid: i32,
-    response_type: RequestResponseType,
+    /// Everything needed to send the request again for a retry.
+    request: RequestBuilder,
+    attempt: u32,
 }
 
+#[cfg(target_arch = "wasm32")]
+const NETWORK_ERROR: u32 = 0;
+#[cfg(target_arch = "wasm32")]
+const ABORTED: u32 = 1;
+#[cfg(target_arch = "wasm32")]
+const TIMEOUT: u32 = 2;
+#[cfg(target_arch = "wasm32")]
+const TOO_LARGE: u32 = 3;
+
 #[cfg(target_arch = "wasm32")]
 impl Request {
-    pub fn try_recv(&mut self) -> Option<Result<ResponsePayload, HttpError>> {
+    pub fn try_recv(&mut self) -> Option<Result<Response, HttpError>> {
         let js_obj = unsafe { http_try_recv(self.cid) };
 
         if js_obj.is_nil() == false {
+            if js_obj.have_field("error") {
+                return self.retry_or(Err(Self::js_error(&js_obj)));
+            }
+
             let mut buf = vec![];
-            js_obj.to_byte_buffer(&mut buf);
+            js_obj.field("body").to_byte_buffer(&mut buf);
 
-            let payload = match self.response_type {
+            let body = match self.request.response_type {
+                RequestResponseType::Stream => ResponsePayload::Stream,
                 RequestResponseType::Text => {
                     let res = std::str::from_utf8(&buf)
                         .unwrap_or("failed_to_decode")
@@ -85,40 +432,284 @@ impl Request {
                 RequestResponseType::Bytes => ResponsePayload::Bytes(buf),
             };
 
-            return Some(Ok(payload));
+            let mut url = String::new();
+            js_obj.field("url").to_string(&mut url);
+
+            // XMLHttpRequest gives all the headers as one CRLF separated string
+            let mut raw_headers = String::new();
+            js_obj.field("headers").to_string(&mut raw_headers);
+            let headers = raw_headers
+                .split("\r\n")
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+                .collect();
+
+            let redirects = match js_obj.field_u32("redirected") {
+                0 => vec![],
+                _ => vec![self.request.url.clone()],
+            };
+
+            let response = Response {
+                status: js_obj.field_u32("status") as u16,
+                headers,
+                url,
+                redirects,
+                stale: false,
+                body,
+            };
+            return self
+                .retry_or(Ok(response))
+                .map(|response| response?.check_status(self.request.http_status_as_error));
         }
 
         None
     }
+
+    /// Pass the outcome of an attempt on, unless the retry policy wants
+    /// another attempt, which then starts in the background.
+    fn retry_or(
+        &mut self,
+        outcome: Result<Response, HttpError>,
+    ) -> Option<Result<Response, HttpError>> {
+        let delay = self.request.retry.as_ref().and_then(|retry| {
+            retry.next_delay(&self.request.method, self.attempt, outcome.as_ref())
+        });
+        match delay {
+            Some(delay) => {
+                WAKERS.with_borrow_mut(|wakers| wakers.remove(&self.cid));
+                unsafe { http_forget(self.cid) };
+                self.cid = self.request.start(delay);
+                self.attempt += 1;
+                None
+            }
+            None => Some(outcome),
+        }
+    }
+
+    /// Next piece of a body requested with `RequestResponseType::Stream`,
+    /// available once `try_recv` returned the response.
+    pub fn try_recv_chunk(&mut self) -> Option<Result<BodyChunk, HttpError>> {
+        let js_obj = unsafe { http_try_recv_chunk(self.cid) };
+
+        if js_obj.is_nil() {
+            return None;
+        }
+        if js_obj.have_field("error") {
+            return Some(Err(Self::js_error(&js_obj)));
+        }
+        if js_obj.have_field("end") {
+            return Some(Ok(BodyChunk::End));
+        }
+
+        let mut buf = vec![];
+        js_obj.field("data").to_byte_buffer(&mut buf);
+        Some(Ok(BodyChunk::Data(buf)))
+    }
+
+    fn js_error(js_obj: &JsObject) -> HttpError {
+        match js_obj.field_u32("error") {
+            NETWORK_ERROR => HttpError::NetworkError,
+            ABORTED => HttpError::Aborted,
+            TIMEOUT => HttpError::Timeout,
+            TOO_LARGE => HttpError::ResponseTooLarge,
+            error => unreachable!("Unknown http error kind {}", error),
+        }
+    }
+
+    /// Bytes of the response body received so far.
+    pub fn progress(&self) -> Progress {
+        self.js_progress("download")
+    }
+
+    /// Bytes of the request body sent so far.
+    pub fn upload_progress(&self) -> Progress {
+        self.js_progress("upload")
+    }
+
+    fn js_progress(&self, direction: &str) -> Progress {
+        let js_obj = unsafe { http_progress(self.cid) };
+        if js_obj.is_nil() {
+            return Progress::default();
+        }
+
+        let progress = js_obj.field(direction);
+        Progress {
+            transferred: progress.field_u32("loaded") as u64,
+            total: progress
+                .have_field("total")
+                .then(|| progress.field_u32("total") as u64),
+        }
+    }
+
+    /// Stop the transfer, `try_recv` will report `HttpError::Aborted`.
+    pub fn cancel(&mut self) {
+        unsafe { http_cancel(self.cid) };
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+thread_local! {
+    /// Tasks awaiting a `Request`, by request id.
+    static WAKERS: std::cell::RefCell<std::collections::HashMap<i32, Waker>> = Default::default();
+}
+
+/// Called by JS once the response of a request is ready.
+#[cfg(target_arch = "wasm32")]
+#[unsafe(no_mangle)]
+extern "C" fn quad_net_http_wake(cid: i32) {
+    if let Some(waker) = WAKERS.with_borrow_mut(|wakers| wakers.remove(&cid)) {
+        waker.wake();
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Future for Request {
+    type Output = Result<Response, HttpError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let request = self.get_mut();
+        match request.try_recv() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                // A retry may have moved the request to a new id
+                WAKERS.with_borrow_mut(|wakers| wakers.insert(request.cid, cx.waker().clone()));
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Drop for Request {
+    fn drop(&mut self) {
+        WAKERS.with_borrow_mut(|wakers| wakers.remove(&self.cid));
+        unsafe { http_forget(self.cid) };
+    }
+}
+
+/// A finished http response, the same on web and desktop.
+///
+/// Any status code the server answered with ends up here, including 3xx and 4xx/5xx,
+/// unless the request was built with `http_status_as_error(true)`.
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    /// Response headers in the order they were received.
+    pub headers: Vec<(String, String)>,
+    /// Final url of the request, after all the redirects were followed.
+    pub url: String,
+    /// Urls that redirected on the way to `url`, starting with the requested one.
+    /// Empty when there was no redirect.
+    ///
+    /// Browsers only tell the final url, so on web this is at most the requested url.
+    pub redirects: Vec<String>,
+    /// Served from the cache past its freshness by a request made with
+    /// `RequestBuilder::stale_while_revalidate`, the fresh response follows.
+    pub stale: bool,
+    pub body: ResponsePayload,
+}
+
+impl Response {
+    /// Value of the first header with the given name, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// True for 2xx status codes.
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Decode the body as json.
+    #[cfg(feature = "json")]
+    pub fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T, HttpError> {
+        let value = match &self.body {
+            ResponsePayload::Text(text) => serde_json::from_str(text)?,
+            ResponsePayload::Bytes(bytes) => serde_json::from_slice(bytes)?,
+            ResponsePayload::Stream => {
+                return Err(HttpError::Json(serde::de::Error::custom(
+                    "streamed body was not buffered",
+                )));
+            }
+        };
+        Ok(value)
+    }
+
+    fn check_status(self, status_as_error: bool) -> Result<Response, HttpError> {
+        if status_as_error && self.status >= 400 {
+            Err(HttpError::Status(Box::new(self)))
+        } else {
+            Ok(self)
+        }
+    }
 }
 
+#[derive(Debug)]
 pub enum ResponsePayload {
     Text(String),
     Bytes(Vec<u8>),
+    /// The body is not buffered, it arrives through `Request::try_recv_chunk`.
+    Stream,
 }
 
 #[derive(Copy, Clone)]
 pub enum RequestResponseType {
     Text,
     Bytes,
+    /// Deliver the body in chunks as it arrives instead of all at once.
+    /// Only a few chunks are buffered, the download waits for the game to
+    /// take them with `Request::try_recv_chunk`.
+    Stream,
 }
 
+#[derive(Clone)]
 pub struct RequestBuilder {
+    client: HttpClient,
     url: String,
     method: Method,
     headers: Vec<(String, String)>,
-    body: Option<String>,
+    body: Option<Vec<u8>>,
     response_type: RequestResponseType,
+    http_status_as_error: bool,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    retry: Option<RetryPolicy>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    max_redirects: Option<u32>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    stale_while_revalidate: bool,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    priority: i32,
+    max_response_bytes: Option<u64>,
 }
 
 impl RequestBuilder {
+    /// Request running on the shared `HttpClient`.
     pub fn new(url: &str) -> RequestBuilder {
+        Self::with_client(HttpClient::shared().clone(), url)
+    }
+
+    fn with_client(client: HttpClient, url: &str) -> RequestBuilder {
         RequestBuilder {
+            client,
             url: url.to_owned(),
             method: Method::Get,
             headers: vec![],
             body: None,
             response_type: RequestResponseType::Text,
+            http_status_as_error: false,
+            timeout: None,
+            connect_timeout: None,
+            read_timeout: None,
+            retry: None,
+            max_redirects: None,
+            stale_while_revalidate: false,
+            priority: 0,
+            max_response_bytes: None,
         }
     }
 
@@ -126,6 +717,8 @@ impl RequestBuilder {
         Self { method, ..self }
     }
 
+    /// Add a header, sent as many times as it is added.
+    /// The defaults of the `HttpClient` only apply to headers left unset.
     pub fn header(mut self, header: &str, value: &str) -> RequestBuilder {
         self.headers.push((header.to_owned(), value.to_owned()));
 
@@ -136,12 +729,72 @@ impl RequestBuilder {
     }
 
     pub fn body(self, body: &str) -> RequestBuilder {
+        self.body_bytes(body.as_bytes().to_vec())
+    }
+
+    /// Raw binary body, sent as is on both web and desktop.
+    pub fn body_bytes(self, body: Vec<u8>) -> RequestBuilder {
         RequestBuilder {
-            body: Some(body.to_owned()),
+            body: Some(body),
             ..self
         }
     }
 
+    /// Shortcut for the `Content-Type` header.
+    pub fn content_type(self, content_type: &str) -> RequestBuilder {
+        self.header("Content-Type", content_type)
+    }
+
+    /// Append a `key=value` pair to the query string, percent-encoded.
+    pub fn query(self, key: &str, value: &str) -> RequestBuilder {
+        let (url, fragment) = match self.url.find('#') {
+            Some(index) => self.url.split_at(index),
+            None => (self.url.as_str(), ""),
+        };
+        let separator = match url.find('?') {
+            Some(index) if index + 1 < url.len() && !url.ends_with('&') => "&",
+            Some(_) => "",
+            None => "?",
+        };
+        let url = format!(
+            "{}{}{}={}{}",
+            url,
+            separator,
+            urlencode(key),
+            urlencode(value),
+            fragment
+        );
+
+        RequestBuilder { url, ..self }
+    }
+
+    /// `application/x-www-form-urlencoded` body, with the matching `Content-Type`.
+    pub fn form(self, fields: &[(&str, &str)]) -> RequestBuilder {
+        let body = fields
+            .iter()
+            .map(|(key, value)| format!("{}={}", urlencode(key), urlencode(value)))
+            .collect::<Vec<_>>()
+            .join("&");
+        self.body(&body)
+            .content_type("application/x-www-form-urlencoded")
+    }
+
+    /// `multipart/form-data` body, with the matching `Content-Type`.
+    pub fn multipart(self, form: Multipart) -> RequestBuilder {
+        self.body_bytes(form.to_bytes())
+            .content_type(&form.content_type())
+    }
+
+    /// Body encoded as json, with the matching `Content-Type`.
+    #[cfg(feature = "json")]
+    pub fn json<T: serde::Serialize + ?Sized>(
+        self,
+        value: &T,
+    ) -> Result<RequestBuilder, HttpError> {
+        let body = serde_json::to_vec(value)?;
+        Ok(self.body_bytes(body).content_type("application/json"))
+    }
+
     pub fn response_type(self, response_type: RequestResponseType) -> RequestBuilder {
         RequestBuilder {
             response_type,
@@ -149,101 +802,897 @@ impl RequestBuilder {
         }
     }
 
+    /// Report 4xx and 5xx responses as `HttpError::Status` instead of `Ok`.
+    pub fn http_status_as_error(self, http_status_as_error: bool) -> RequestBuilder {
+        RequestBuilder {
+            http_status_as_error,
+            ..self
+        }
+    }
+
+    /// Deadline for the whole request, from sending it to the last byte of the body.
+    pub fn timeout(self, timeout: Duration) -> RequestBuilder {
+        RequestBuilder {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Limit on establishing the connection.
+    /// Browsers do not expose the connection phase, so on web this is the time
+    /// until the response headers arrive.
+    pub fn connect_timeout(self, timeout: Duration) -> RequestBuilder {
+        RequestBuilder {
+            connect_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Limit on waiting for the response once the request is sent, then on the
+    /// silence between two chunks of the body. Slow downloads go on as long as
+    /// data keeps arriving.
+    pub fn read_timeout(self, timeout: Duration) -> RequestBuilder {
+        RequestBuilder {
+            read_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Send the request again when it fails in a way the policy considers
+    /// temporary.
+    pub fn retry(self, retry: RetryPolicy) -> RequestBuilder {
+        RequestBuilder {
+            retry: Some(retry),
+            ..self
+        }
+    }
+
+    /// Follow at most `max_redirects` redirects, failing with `HttpError::TooManyRedirects`
+    /// after that. With 0 redirects are not followed at all, and the 3xx response
+    /// is returned as is. Defaults to 10.
+    ///
+    /// Desktop only, browsers always follow redirects by themselves.
+    pub fn max_redirects(self, max_redirects: u32) -> RequestBuilder {
+        RequestBuilder {
+            max_redirects: Some(max_redirects),
+            ..self
+        }
+    }
+
+    /// With a cached response past its freshness, hand it over right away
+    /// flagged as `Response::stale`, then send the request and hand over its
+    /// outcome as a second result: `try_recv` returns twice, or the `Request`
+    /// can be awaited twice. Keeps screens filled while offline, when the
+    /// second result is the error.
+    ///
+    /// Fresh cached responses are returned alone as usual. Needs a client built
+    /// with `HttpClientBuilder::cache`, so this is desktop only.
+    pub fn stale_while_revalidate(self, stale_while_revalidate: bool) -> RequestBuilder {
+        RequestBuilder {
+            stale_while_revalidate,
+            ..self
+        }
+    }
+
+    /// Fail with `HttpError::ResponseTooLarge` instead of receiving a body
+    /// larger than `max_response_bytes`. With streamed bodies the error arrives
+    /// as a chunk, once the limit is crossed.
+    ///
+    /// On web bodies that are not streamed may be counted as they travel over
+    /// the network, before the browser decompresses them.
+    pub fn max_response_bytes(self, max_response_bytes: u64) -> RequestBuilder {
+        RequestBuilder {
+            max_response_bytes: Some(max_response_bytes),
+            ..self
+        }
+    }
+
+    /// Place among the requests waiting for the rate limit of their host,
+    /// higher goes first, see `HttpClientBuilder::rate_limit`. Defaults to 0.
+    ///
+    /// Desktop only, like the rate limits.
+    pub fn priority(self, priority: i32) -> RequestBuilder {
+        RequestBuilder { priority, ..self }
+    }
+
     #[cfg(not(target_arch = "wasm32"))]
     pub fn send(self) -> Request {
-        use std::sync::mpsc::channel;
+        use std::sync::mpsc::{channel, sync_channel};
 
         let (tx, rx) = channel();
+        let (chunks_tx, chunks_rx) = sync_channel(STREAM_BUFFERED_CHUNKS);
+        let state = Arc::new(RequestState::default());
 
-        std::thread::spawn(move || {
-            let response: Result<ResponsePayload, HttpError> = match self.method {
-                // Methods that can have a body
-                Method::Post | Method::Put => {
-                    let mut request = match self.method {
-                        Method::Post => ureq::post(&self.url),
-                        Method::Put => ureq::put(&self.url),
-                        _ => unreachable!(),
-                    };
-
-                    // Set headers
-                    for (header, value) in &self.headers {
-                        request = request.header(header, value);
-                    }
-
-                    // Send with or without body
-
-                    if let Some(body) = &self.body {
-                        request.send(body)
-                    } else {
-                        request.send_empty()
-                    }
-                }
-                // Methods that cannot have a body
-                Method::Get | Method::Delete => {
-                    let mut request = match self.method {
-                        Method::Get => ureq::get(&self.url),
-                        Method::Delete => ureq::delete(&self.url),
-                        _ => unreachable!(),
-                    };
-
-                    // Set headers
-                    for (header, value) in &self.headers {
-                        request = request.header(header, value);
-                    }
-
-                    request.call()
-                }
+        let host = ureq::http::Uri::try_from(&self.url)
+            .ok()
+            .and_then(|uri| uri.host().map(str::to_owned));
+        let request = Request {
+            rx,
+            tx: tx.clone(),
+            chunks_rx: Some(chunks_rx),
+            state: state.clone(),
+        };
+        let exchange = Exchange {
+            request: self.with_default_headers(),
+            host,
+            state,
+            tx,
+            chunks_tx,
+            cached: None,
+        };
+        match exchange.request.cache() {
+            // Looked up on a worker, straight from the pool: hits need no
+            // turn of the rate limit
+            Some(_) => {
+                let client = exchange.request.client.clone();
+                let state = exchange.state.clone();
+                client.spawn(None, 0, state, Duration::ZERO, move || exchange.lookup());
             }
-            .map_err(|err| err.into())
-            .and_then(|mut response| match self.response_type {
-                RequestResponseType::Text => response
-                    .body_mut()
-                    .read_to_string()
-                    .map(ResponsePayload::Text)
-                    .map_err(|err| err.into()),
-                RequestResponseType::Bytes => {
-                    let mut bytes = Vec::new();
-                    match response.into_body().into_reader().read_to_end(&mut bytes) {
-                        Ok(_) => Ok(ResponsePayload::Bytes(bytes)),
-                        Err(err) => Err(err.into()),
-                    }
-                }
+            None => Arc::new(exchange).spawn(1, Duration::ZERO),
+        }
+        request
+    }
+
+    /// Run the request up to the response.
+    /// Streamed responses come back with the body reader still to be read.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn execute(
+        &self,
+        state: &RequestState,
+        cached: Option<cache::CachedResponse>,
+    ) -> Result<(Response, Option<Box<dyn Read + Send>>), HttpError> {
+        if state.is_cancelled() {
+            return Err(HttpError::Aborted);
+        }
+        // Start from scratch on retries
+        *state.download.lock().unwrap() = Progress::default();
+        *state.upload.lock().unwrap() = Progress::default();
+
+        let agent = self.client.agent();
+
+        // Fresh entries were served by `Exchange::lookup`, the others get revalidated
+        let cache = self.cache();
+
+        let mut headers = self.request_headers();
+        // Taken before the cookies of the response land in the jar
+        let cache_headers = cache.map(|_| self.cache_headers()).unwrap_or_default();
+
+        // Ask the server whether the cached body is still good, unless the
+        // request has its own conditions, then a 304 is theirs to handle
+        let has_condition = self.headers.iter().any(|(header, _)| {
+            header.eq_ignore_ascii_case("If-None-Match")
+                || header.eq_ignore_ascii_case("If-Modified-Since")
+        });
+        let cached = cached.filter(|_| !has_condition);
+        if let Some(cached) = &cached {
+            if let Some(etag) = cached.header("ETag") {
+                headers.push(("If-None-Match".to_owned(), etag.to_owned()));
+            }
+            if let Some(last_modified) = cached.header("Last-Modified") {
+                headers.push(("If-Modified-Since".to_owned(), last_modified.to_owned()));
+            }
+        }
+
+        let mut method = self.method.clone();
+        let mut url = self.url.clone();
+        let mut body = self.body.as_deref().filter(|_| self.method.allows_body());
+        let mut redirects = vec![];
+        let response = loop {
+            let response = self.send_hop(agent, state, &method, &url, &headers, body)?;
+            let Some((next_url, next_method)) =
+                self.follow(&url, &method, &response, redirects.len())?
+            else {
+                break response;
+            };
+            // Credentials are for the requested url only, the cookies of
+            // the next hop come from the jar
+            headers.retain(|(header, _)| {
+                !header.eq_ignore_ascii_case("Authorization")
+                    && !header.eq_ignore_ascii_case("Cookie")
             });
+            if next_method != method {
+                body = None;
+            }
+            method = next_method;
+            redirects.push(std::mem::replace(&mut url, next_url));
+        };
+
+        let status = response.status().as_u16();
+        let url = response.get_uri().to_string();
+        // Redirects followed by ureq, the history ends with the final url,
+        // which is already in `url`
+        if let Some([history @ .., _]) = response.get_redirect_history() {
+            redirects.extend(history.iter().map(|uri| uri.to_string()));
+        }
+        let headers: Vec<(String, String)> = response
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.as_str().to_owned(),
+                    String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                )
+            })
+            .collect();
+
+        let body = response.into_body();
+        // Decompressed as it is read
+        #[cfg(feature = "compression")]
+        let content_encoding = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Encoding"))
+            .map(|(_, value)| value.clone())
+            .filter(|encoding| encoding::is_supported(encoding));
+        #[cfg(not(feature = "compression"))]
+        let content_encoding: Option<String> = None;
+        // The announced length is the compressed one, it says nothing of the decoded body
+        let length = body.content_length().filter(|_| content_encoding.is_none());
+
+        state.download.lock().unwrap().total = length;
+        // No need to download what is announced too large
+        if let (Some(limit), Some(length)) = (self.max_response_bytes, length)
+            && length > limit
+        {
+            return Err(HttpError::ResponseTooLarge);
+        }
+        let reader: Box<dyn Read + Send> = match content_encoding {
+            #[cfg(feature = "compression")]
+            Some(content_encoding) => Box::new(encoding::Decoder::new(
+                &content_encoding,
+                body.into_reader(),
+            )),
+            _ => Box::new(body.into_reader()),
+        };
+
+        if let RequestResponseType::Stream = self.response_type {
+            let response = Response {
+                status,
+                headers,
+                url,
+                redirects,
+                stale: false,
+                body: ResponsePayload::Stream,
+            };
+            return Ok((response, Some(reader)));
+        }
+
+        if let (Some(cache), Some(cached)) = (cache, cached)
+            && status == 304
+        {
+            let cached = cache.revalidated(&self.url, &cache_headers, cached, &headers);
+            return self
+                .cached_response(cached, state)
+                .map(|response| (response, None));
+        }
+
+        let mut reader = TransferReader {
+            inner: reader,
+            state,
+            progress: &state.download,
+            limit: self.max_response_bytes,
+            idle_timeout: self.read_timeout,
+        };
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        if let Some(cache) = cache
+            && status == 200
+        {
+            cache.store(&self.url, &cache_headers, &url, &headers, &bytes);
+        }
+
+        let response = Response {
+            status,
+            headers,
+            url,
+            redirects,
+            stale: false,
+            body: self.payload(bytes)?,
+        };
+        Ok((response, None))
+    }
+
+    /// The client's cache, if it applies to this request.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn cache(&self) -> Option<&cache::HttpCache> {
+        self.client.cache().filter(|_| {
+            self.method == Method::Get
+                && !matches!(self.response_type, RequestResponseType::Stream)
+                && cache::cache_control(&self.headers, "no-store").is_none()
+        })
+    }
+
+    /// Headers of the request, with the defaults desktop adds.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn request_headers(&self) -> Vec<(String, String)> {
+        #[cfg_attr(not(feature = "compression"), allow(unused_mut))]
+        let mut headers = self.headers.clone();
+        #[cfg(feature = "compression")]
+        if !self
+            .headers
+            .iter()
+            .any(|(header, _)| header.eq_ignore_ascii_case("Accept-Encoding"))
+        {
+            headers.push((
+                "Accept-Encoding".to_owned(),
+                encoding::ACCEPT_ENCODING.to_owned(),
+            ));
+        }
+        headers
+    }
+
+    /// Headers for the cache to match against the `Vary` of the responses,
+    /// the cookies of the client included.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn cache_headers(&self) -> Vec<(String, String)> {
+        #[cfg_attr(not(feature = "cookies"), allow(unused_mut))]
+        let mut headers = self.request_headers();
+        #[cfg(feature = "cookies")]
+        if let Some(cookies) = self
+            .client
+            .cookie_jar()
+            .and_then(|jar| cookies::header(jar, &self.url))
+        {
+            headers.push(("Cookie".to_owned(), cookies));
+        }
+        headers
+    }
+
+    /// Response served from the cache, as if it was just downloaded.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn cached_response(
+        &self,
+        cached: cache::CachedResponse,
+        state: &RequestState,
+    ) -> Result<Response, HttpError> {
+        let size = cached.body.len() as u64;
+        if self.max_response_bytes.is_some_and(|limit| size > limit) {
+            return Err(HttpError::ResponseTooLarge);
+        }
+        *state.download.lock().unwrap() = Progress {
+            transferred: size,
+            total: Some(size),
+        };
+        Ok(Response {
+            status: 200,
+            headers: cached.headers,
+            url: cached.url,
+            redirects: vec![],
+            stale: false,
+            body: self.payload(cached.body)?,
+        })
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn payload(&self, bytes: Vec<u8>) -> Result<ResponsePayload, HttpError> {
+        match self.response_type {
+            RequestResponseType::Text => String::from_utf8(bytes)
+                .map(ResponsePayload::Text)
+                .map_err(|_| HttpError::IOError),
+            RequestResponseType::Bytes => Ok(ResponsePayload::Bytes(bytes)),
+            RequestResponseType::Stream => unreachable!(),
+        }
+    }
+
+    /// Keep the cookies set by `response`, and give the url and method to
+    /// go on with if it redirects. Only for clients with cookies, ureq
+    /// follows the redirects of the others.
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg_attr(not(feature = "cookies"), allow(unused_variables))]
+    fn follow(
+        &self,
+        url: &str,
+        method: &Method,
+        response: &ureq::http::Response<ureq::Body>,
+        followed: usize,
+    ) -> Result<Option<(String, Method)>, HttpError> {
+        #[cfg(feature = "cookies")]
+        if let Some(jar) = self.client.cookie_jar() {
+            let headers = response.headers();
+            let set_cookies = headers
+                .get_all(ureq::http::header::SET_COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok());
+            cookies::store(jar, url, set_cookies);
 
-            let result = tx.send(response);
-            if let Err(e) = result {
-                log::error!("Http request receiver dropped {:?}", e);
+            let max_redirects = self.max_redirects.unwrap_or(DEFAULT_MAX_REDIRECTS) as usize;
+            let location = headers
+                .get(ureq::http::header::LOCATION)
+                .and_then(|value| value.to_str().ok());
+            let next = cookies::redirect(url, method, response.status().as_u16(), location)
+                .filter(|_| max_redirects > 0);
+            if next.is_some() && followed == max_redirects {
+                return Err(HttpError::TooManyRedirects);
             }
-        });
-        Request { rx }
+            return Ok(next);
+        }
+        Ok(None)
+    }
+
+    /// Send a single request on the way to the response, with the body if
+    /// there is one, and the client's cookies.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn send_hop(
+        &self,
+        agent: &ureq::Agent,
+        state: &RequestState,
+        method: &Method,
+        url: &str,
+        headers: &[(String, String)],
+        body: Option<&[u8]>,
+    ) -> Result<ureq::http::Response<ureq::Body>, HttpError> {
+        let mut request = ureq::http::Request::builder()
+            .method(method.as_str())
+            .uri(url);
+        for (header, value) in headers {
+            request = request.header(header, value);
+        }
+        #[cfg(feature = "cookies")]
+        if let Some(cookies) = self
+            .client
+            .cookie_jar()
+            .and_then(|jar| cookies::header(jar, url))
+        {
+            request = request.header("Cookie", cookies);
+        }
+
+        // Send with or without body
+        let response = match body {
+            Some(body) => {
+                // The body is streamed through a reader to be able to stop
+                // mid-upload, so ureq needs to be told its length
+                if let Some(headers) = request.headers_mut() {
+                    headers.insert(ureq::http::header::CONTENT_LENGTH, body.len().into());
+                }
+                *state.upload.lock().unwrap() = Progress {
+                    transferred: 0,
+                    total: Some(body.len() as u64),
+                };
+                let mut reader = TransferReader {
+                    inner: body,
+                    state,
+                    progress: &state.upload,
+                    limit: None,
+                    idle_timeout: None,
+                };
+                let request = request.body(ureq::SendBody::from_reader(&mut reader))?;
+                agent.run(self.configure(agent, request))
+            }
+            None => agent.run(self.configure(agent, request.body(())?)),
+        }?;
+        Ok(response)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn configure<S: ureq::AsSendBody>(
+        &self,
+        agent: &ureq::Agent,
+        request: ureq::http::Request<S>,
+    ) -> ureq::http::Request<S> {
+        let request_url = request.uri().to_string();
+        let mut config = agent
+            .configure_request(request)
+            .timeout_global(self.timeout)
+            .timeout_connect(self.connect_timeout)
+            .timeout_recv_response(self.read_timeout)
+            .proxy(self.client.proxy(&request_url))
+            .save_redirect_history(true);
+        if let Some(max_redirects) = self.max_redirects {
+            config = config.max_redirects(max_redirects);
+        }
+        // Clients with cookies follow the redirects in `execute`
+        #[cfg(feature = "cookies")]
+        if self.client.cookie_jar().is_some() {
+            config = config.max_redirects(0);
+        }
+        config.build()
     }
 
     #[cfg(target_arch = "wasm32")]
     pub fn send(&self) -> Request {
-        let scheme = match self.method {
-            Method::Post => 0,
-            Method::Put => 1,
-            Method::Get => 2,
-            Method::Delete => 3,
-        };
+        let request = self.clone().with_default_headers();
+        Request {
+            cid: request.start(Duration::ZERO),
+            request,
+            attempt: 1,
+        }
+    }
+
+    /// Add the client's default headers the request did not set itself.
+    fn with_default_headers(mut self) -> RequestBuilder {
+        let defaults = self.client.headers().iter().filter(|(default, _)| {
+            !self
+                .headers
+                .iter()
+                .any(|(header, _)| header.eq_ignore_ascii_case(default))
+        });
+        let mut headers: Vec<_> = defaults.cloned().collect();
+        headers.append(&mut self.headers);
 
+        RequestBuilder { headers, ..self }
+    }
+
+    /// Hand the request over to JS, to be sent after `delay`.
+    #[cfg(target_arch = "wasm32")]
+    fn start(&self, delay: Duration) -> i32 {
         let headers = JsObject::object();
 
         for (header, value) in &self.headers {
             headers.set_field_string(&header, &value);
         }
 
-        let cid = unsafe {
+        let options = JsObject::object();
+        let millis = |duration: Duration| duration.as_millis().min(MAX_TIMER_MS as u128) as u32;
+        // 0 is no timeout at all
+        let timeout_millis =
+            |timeout: Option<Duration>| timeout.map_or(0, |timeout| millis(timeout).max(1));
+        options.set_field_u32("timeout_ms", timeout_millis(self.timeout));
+        options.set_field_u32("connect_timeout_ms", timeout_millis(self.connect_timeout));
+        options.set_field_u32("read_timeout_ms", timeout_millis(self.read_timeout));
+        options.set_field_u32(
+            "stream",
+            matches!(self.response_type, RequestResponseType::Stream) as u32,
+        );
+        options.set_field_u32("delay_ms", millis(delay));
+        if let Some(limit) = self.max_response_bytes {
+            // Browsers can not hold bodies past 4GB in memory anyway
+            options.set_field_u32("max_response_bytes", limit.min(u32::MAX as u64) as u32);
+        }
+
+        unsafe {
             http_make_request(
-                scheme,
+                JsObject::string(self.method.as_str()),
                 JsObject::string(&self.url),
-                JsObject::string(&self.body.as_ref().map(|s| s.as_str()).unwrap_or("")),
+                JsObject::buffer(
+                    self.body
+                        .as_deref()
+                        .filter(|_| self.method.allows_body())
+                        .unwrap_or(&[]),
+                ),
                 headers,
+                options,
             )
+        }
+    }
+}
+
+/// A request sent on desktop, shared by all of its attempts.
+#[cfg(not(target_arch = "wasm32"))]
+struct Exchange {
+    request: RequestBuilder,
+    host: Option<String>,
+    state: Arc<RequestState>,
+    tx: std::sync::mpsc::Sender<Result<Response, HttpError>>,
+    chunks_tx: std::sync::mpsc::SyncSender<Result<BodyChunk, HttpError>>,
+    /// Stale cache entry, to revalidate.
+    cached: Option<cache::CachedResponse>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Exchange {
+    /// Answer from the cache if the entry is fresh, otherwise hand over the
+    /// stale one if asked to and go on with the request.
+    fn lookup(mut self) {
+        if self.state.is_cancelled() {
+            return;
+        }
+        let request = &self.request;
+        let Some(cached) = request
+            .cache()
+            .and_then(|cache| cache.get(&request.url, &request.cache_headers()))
+        else {
+            return Arc::new(self).spawn(1, Duration::ZERO);
         };
-        Request {
-            cid,
-            response_type: self.response_type,
+
+        if cached.is_fresh() && cache::cache_control(&request.headers, "no-cache").is_none() {
+            if self.state.finish() {
+                let _ = self.tx.send(request.cached_response(cached, &self.state));
+                self.state.wake();
+            }
+            return;
+        }
+        if request.stale_while_revalidate
+            && let Ok(response) = request.cached_response(cached.clone(), &self.state)
+        {
+            let _ = self.tx.send(Ok(Response {
+                stale: true,
+                ..response
+            }));
+            self.state.wake();
+        }
+        self.cached = Some(cached);
+        Arc::new(self).spawn(1, Duration::ZERO);
+    }
+
+    /// Queue attempt number `attempt` on the client, to run after `delay`.
+    fn spawn(self: Arc<Self>, attempt: u32, delay: Duration) {
+        let client = self.request.client.clone();
+        let host = self.host.clone();
+        let priority = self.request.priority;
+        let state = self.state.clone();
+        client.spawn(host.as_deref(), priority, state, delay, move || {
+            self.run(attempt)
+        });
+    }
+
+    fn run(self: Arc<Self>, attempt: u32) {
+        let request = &self.request;
+        let state = &self.state;
+        let outcome = watchdog::watch(state, || request.execute(state, self.cached.clone()));
+        if let (Some(limiter), Some(host), Ok((response, _))) =
+            (request.client.rate_limiter(), &self.host, &outcome)
+            && matches!(response.status, 429 | 503)
+            && let Some(retry_after) = retry::retry_after(response)
+        {
+            limiter.pause(host, retry_after);
+        }
+        let delay = request.retry.as_ref().and_then(|retry| {
+            let outcome = outcome.as_ref().map(|(response, _)| response);
+            retry.next_delay(&request.method, attempt, outcome)
+        });
+        // The worker is free for other requests during the backoff
+        if let Some(delay) = delay
+            && !state.is_cancelled()
+        {
+            drop(outcome);
+            self.spawn(attempt + 1, delay);
+            return;
+        }
+
+        // `Request::cancel` already reported the abort
+        if !state.finish() {
+            return;
+        }
+        let (response, stream) = match outcome {
+            Ok((response, stream)) => (response.check_status(request.http_status_as_error), stream),
+            Err(err) => (Err(err), None),
+        };
+
+        let result = self.tx.send(response);
+        if let Err(e) = result {
+            log::error!("Http request receiver dropped {:?}", e);
+        }
+        state.wake();
+
+        // Read on a thread of its own, the game may take its time with the
+        // chunks and the worker is needed for other requests
+        if let Some(stream) = stream {
+            std::thread::Builder::new()
+                .name("quad-net-http-stream".to_owned())
+                .spawn(move || {
+                    let request = &self.request;
+                    watchdog::watch(&self.state, || {
+                        stream_body(
+                            stream,
+                            request.max_response_bytes,
+                            request.read_timeout,
+                            &self.state,
+                            &self.chunks_tx,
+                        )
+                    })
+                })
+                .expect("Failed to spawn http stream thread");
+        }
+    }
+}
+
+/// Encoding of `application/x-www-form-urlencoded`, used for both query strings and forms.
+fn urlencode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'*' => {
+                encoded.push(byte as char)
+            }
+            b' ' => encoded.push('+'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Read a streamed response body into the chunks channel until it ends,
+/// fails, or nobody is listening anymore.
+#[cfg(not(target_arch = "wasm32"))]
+fn stream_body(
+    body: impl Read,
+    limit: Option<u64>,
+    idle_timeout: Option<Duration>,
+    state: &RequestState,
+    chunks_tx: &std::sync::mpsc::SyncSender<Result<BodyChunk, HttpError>>,
+) {
+    let mut reader = TransferReader {
+        inner: body,
+        state,
+        progress: &state.download,
+        limit,
+        idle_timeout,
+    };
+    loop {
+        let mut data = vec![0; STREAM_CHUNK_SIZE];
+        let chunk = match reader.read(&mut data) {
+            Ok(0) => Ok(BodyChunk::End),
+            Ok(read) => {
+                data.truncate(read);
+                Ok(BodyChunk::Data(data))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_) if state.is_cancelled() => Err(HttpError::Aborted),
+            Err(err) => Err(err.into()),
+        };
+
+        let last = !matches!(chunk, Ok(BodyChunk::Data(_)));
+        // Blocks while the channel is full, until the game takes a chunk or cancels
+        if chunks_tx.send(chunk).is_err() || last {
+            break;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url_with(url: &str, query: &[(&str, &str)]) -> String {
+        query
+            .iter()
+            .fold(RequestBuilder::new(url), |request, (key, value)| {
+                request.query(key, value)
+            })
+            .url
+    }
+
+    #[test]
+    fn urlencoding() {
+        assert_eq!(urlencode("AZaz09-._*"), "AZaz09-._*");
+        assert_eq!(urlencode("a b"), "a+b");
+        assert_eq!(urlencode("a+b&c=d/e?f#g"), "a%2Bb%26c%3Dd%2Fe%3Ff%23g");
+        assert_eq!(urlencode("été ~"), "%C3%A9t%C3%A9+%7E");
+        assert_eq!(urlencode(""), "");
+    }
+
+    #[test]
+    fn query() {
+        let url = "http://example.com/search";
+        assert_eq!(url_with(url, &[("q", "a b")]), format!("{}?q=a+b", url));
+        assert_eq!(
+            url_with(url, &[("q", "1"), ("page", "2")]),
+            format!("{}?q=1&page=2", url)
+        );
+        assert_eq!(
+            url_with(&format!("{}?lang=fr", url), &[("q", "&")]),
+            format!("{}?lang=fr&q=%26", url)
+        );
+        assert_eq!(
+            url_with(&format!("{}?", url), &[("q", "1")]),
+            format!("{}?q=1", url)
+        );
+        assert_eq!(
+            url_with(&format!("{}?lang=fr&", url), &[("q", "1")]),
+            format!("{}?lang=fr&q=1", url)
+        );
+    }
+
+    #[test]
+    fn query_before_fragment() {
+        assert_eq!(
+            url_with("http://example.com/page#top", &[("q", "#")]),
+            "http://example.com/page?q=%23#top"
+        );
+        assert_eq!(
+            url_with("http://example.com/page?lang=fr#top", &[("q", "1")]),
+            "http://example.com/page?lang=fr&q=1#top"
+        );
+    }
+
+    #[test]
+    fn form() {
+        let request = RequestBuilder::new("http://example.com")
+            .form(&[("name", "Jane Doe"), ("note", "1+1=2")]);
+        assert_eq!(
+            request.body.as_deref(),
+            Some(&b"name=Jane+Doe&note=1%2B1%3D2"[..])
+        );
+        assert_eq!(
+            request.headers,
+            [(
+                "Content-Type".to_owned(),
+                "application/x-www-form-urlencoded".to_owned()
+            )]
+        );
+    }
+
+    #[test]
+    fn default_headers() {
+        let client = HttpClientBuilder::new()
+            .header("Accept", "text/plain")
+            .user_agent("game")
+            .build();
+        let request = client
+            .request("http://example.com")
+            .header("accept", "image/png")
+            .header("Accept", "image/webp")
+            .header("X-Tag", "1")
+            .with_default_headers();
+        let headers: Vec<_> = request
+            .headers
+            .iter()
+            .map(|(header, value)| (header.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(
+            headers,
+            [
+                ("User-Agent", "game"),
+                ("accept", "image/png"),
+                ("Accept", "image/webp"),
+                ("X-Tag", "1")
+            ]
+        );
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn in_flight() -> (
+        Request,
+        std::sync::mpsc::Sender<Result<Response, HttpError>>,
+    ) {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (_, chunks_rx) = std::sync::mpsc::sync_channel(1);
+        let request = Request {
+            rx,
+            tx: tx.clone(),
+            chunks_rx: Some(chunks_rx),
+            state: Arc::default(),
+        };
+        (request, tx)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn cancel() {
+        // Too late, the outcome is already in
+        let (mut request, tx) = in_flight();
+        assert!(request.state.finish());
+        tx.send(Err(HttpError::Timeout)).unwrap();
+        request.cancel();
+        assert!(request.state.is_cancelled());
+        assert!(matches!(request.try_recv(), Some(Err(HttpError::Timeout))));
+        assert!(request.try_recv().is_none());
+
+        // In time, the outcome is left unsent
+        let (mut request, _) = in_flight();
+        request.cancel();
+        assert!(!request.state.finish());
+        assert!(matches!(request.try_recv(), Some(Err(HttpError::Aborted))));
+        request.cancel();
+        assert!(request.try_recv().is_none());
+    }
+
+    #[cfg(all(feature = "compression", not(target_arch = "wasm32")))]
+    #[test]
+    fn limit_counts_decompressed_bytes() {
+        use std::io::Write;
+
+        let body = [b'a'; 1000];
+        let mut gzip = flate2::write::GzEncoder::new(vec![], flate2::Compression::best());
+        gzip.write_all(&body).unwrap();
+        let gzip = gzip.finish().unwrap();
+        assert!(gzip.len() < 100);
+
+        let state = RequestState::default();
+        let read = |limit| {
+            let mut reader = TransferReader {
+                inner: encoding::Decoder::new("gzip", std::io::Cursor::new(gzip.clone())),
+                state: &state,
+                progress: &state.download,
+                limit: Some(limit),
+                idle_timeout: None,
+            };
+            *state.download.lock().unwrap() = Progress::default();
+            let mut bytes = vec![];
+            reader.read_to_end(&mut bytes).map(|_| bytes)
+        };
+
+        assert_eq!(read(1000).unwrap(), body);
+        let error = HttpError::from(read(100).unwrap_err());
+        assert!(matches!(error, HttpError::ResponseTooLarge));
+    }
+}
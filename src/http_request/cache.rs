@@ -0,0 +1,499 @@
+//! On-disk cache for GET responses, desktop only.
+//!
+//! Responses are stored by url, along with the values the request had for the
+//! headers listed in the response's `Vary`: a request with other values misses
+//! and replaces the entry. Fresh ones, per `Cache-Control` or `Expires`, are
+//! returned without touching the network. Stale ones are revalidated with
+//! `If-None-Match` / `If-Modified-Since`, the server answering 304 when the
+//! stored body is still good.
+//!
+//! Every entry is a file in the cache directory, when the directory grows past
+//! its size limit the least recently used entries are removed.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+const FORMAT: &str = "quad-net-cache 2";
+
+/// Without any freshness information, responses with a `Last-Modified` are
+/// fresh for a tenth of their age, up to this long.
+const MAX_HEURISTIC_FRESHNESS: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Longer lifetimes are as good as forever, and keep clear of `SystemTime` overflows.
+const MAX_FRESHNESS: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+pub(super) struct HttpCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+#[derive(Clone)]
+pub(super) struct CachedResponse {
+    pub(super) url: String,
+    pub(super) headers: Vec<(String, String)>,
+    pub(super) body: Vec<u8>,
+    /// Values of the request headers named by `Vary`, see `varied`.
+    vary: Vec<(String, String)>,
+    /// When the response stops being fresh.
+    pub(super) expires: SystemTime,
+}
+
+impl CachedResponse {
+    pub(super) fn is_fresh(&self) -> bool {
+        SystemTime::now() < self.expires
+    }
+
+    pub(super) fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Whether `Cache-Control` in `headers` has `directive`, and its value if there is one.
+pub(super) fn cache_control<'a>(
+    headers: &'a [(String, String)],
+    directive: &str,
+) -> Option<Option<&'a str>> {
+    headers
+        .iter()
+        .filter(|(header, _)| header.eq_ignore_ascii_case("Cache-Control"))
+        .flat_map(|(_, value)| value.split(','))
+        .find_map(|item| {
+            let (name, value) = match item.split_once('=') {
+                Some((name, value)) => (name, Some(value.trim().trim_matches('"'))),
+                None => (item, None),
+            };
+            name.trim().eq_ignore_ascii_case(directive).then_some(value)
+        })
+}
+
+/// The headers of `request_headers` named by the `Vary` of `response_headers`,
+/// each with all its values joined. Requests match a stored response when
+/// this comes out the same.
+fn varied(
+    response_headers: &[(String, String)],
+    request_headers: &[(String, String)],
+) -> Vec<(String, String)> {
+    response_headers
+        .iter()
+        .filter(|(header, _)| header.eq_ignore_ascii_case("Vary"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| {
+            let values: Vec<&str> = request_headers
+                .iter()
+                .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.trim())
+                .collect();
+            (!values.is_empty()).then(|| (name.to_ascii_lowercase(), values.join(", ")))
+        })
+        .collect()
+}
+
+/// Whether `name` is the name of an entry file, as made by `HttpCache::path`.
+fn is_entry_name(name: &str) -> bool {
+    name.len() == 32 && name.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn http_date(headers: &[(String, String)], name: &str) -> Option<SystemTime> {
+    header(headers, name).and_then(|value| httpdate::parse_http_date(value).ok())
+}
+
+/// How long a response stays fresh, counted from when it was received.
+fn freshness(headers: &[(String, String)]) -> Duration {
+    if cache_control(headers, "no-cache").is_some() {
+        return Duration::ZERO;
+    }
+    let age = header(headers, "Age")
+        .and_then(|age| age.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+    let date = http_date(headers, "Date").unwrap_or_else(SystemTime::now);
+
+    let lifetime = if let Some(max_age) = cache_control(headers, "max-age").flatten() {
+        Duration::from_secs(max_age.parse().unwrap_or(0))
+    } else if let Some(expires) = header(headers, "Expires") {
+        // Invalid dates, like "0", mean already expired
+        httpdate::parse_http_date(expires)
+            .ok()
+            .and_then(|expires| expires.duration_since(date).ok())
+            .unwrap_or_default()
+    } else if let Some(last_modified) = http_date(headers, "Last-Modified") {
+        (date.duration_since(last_modified).unwrap_or_default() / 10).min(MAX_HEURISTIC_FRESHNESS)
+    } else {
+        Duration::ZERO
+    };
+    lifetime.min(MAX_FRESHNESS).saturating_sub(age)
+}
+
+impl HttpCache {
+    pub(super) fn new(dir: PathBuf, max_size: u64) -> HttpCache {
+        HttpCache { dir, max_size }
+    }
+
+    fn path(&self, url: &str) -> PathBuf {
+        let hash = ring::digest::digest(&ring::digest::SHA256, url.as_bytes());
+        let name: String = hash.as_ref()[..16]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        self.dir.join(name)
+    }
+
+    /// The response stored for `url`, if it was for a request with the same
+    /// `request_headers` as far as its `Vary` goes.
+    pub(super) fn get(
+        &self,
+        url: &str,
+        request_headers: &[(String, String)],
+    ) -> Option<CachedResponse> {
+        let path = self.path(url);
+        let file = fs::File::open(&path).ok()?;
+        // Last access time, for the eviction
+        let _ = file.set_modified(SystemTime::now());
+
+        let mut reader = BufReader::new(file);
+        let mut lines = (&mut reader).lines();
+        if lines.next()?.ok()? != FORMAT || lines.next()?.ok()? != url {
+            return None;
+        }
+        let final_url = lines.next()?.ok()?;
+        let expires =
+            SystemTime::UNIX_EPOCH + Duration::from_secs(lines.next()?.ok()?.parse().ok()?);
+        let vary = read_headers(&mut lines)?;
+        let headers = read_headers(&mut lines)?;
+        if vary != varied(&headers, request_headers) {
+            return None;
+        }
+        let mut body = vec![];
+        reader.read_to_end(&mut body).ok()?;
+
+        Some(CachedResponse {
+            url: final_url,
+            headers,
+            body,
+            vary,
+            expires,
+        })
+    }
+
+    /// Keep a 200 response to a GET with `request_headers`, unless its headers
+    /// forbid it. Even responses that are never fresh are kept, for
+    /// `stale_while_revalidate`.
+    pub(super) fn store(
+        &self,
+        url: &str,
+        request_headers: &[(String, String)],
+        final_url: &str,
+        headers: &[(String, String)],
+        body: &[u8],
+    ) {
+        if cache_control(headers, "no-store").is_some()
+            || header(headers, "Vary").is_some_and(|vary| vary.trim() == "*")
+            // Answers to a signed in user are theirs only, unless said otherwise
+            || (header(request_headers, "Authorization").is_some()
+                && cache_control(headers, "public").is_none())
+            || body.len() as u64 > self.max_size
+        {
+            let _ = fs::remove_file(self.path(url));
+            return;
+        }
+
+        let entry = CachedResponse {
+            url: final_url.to_owned(),
+            headers: headers.to_vec(),
+            body: body.to_vec(),
+            vary: varied(headers, request_headers),
+            expires: SystemTime::now() + freshness(headers),
+        };
+        if let Err(err) = self.write(url, &entry) {
+            log::warn!("Failed to cache {}: {}", url, err);
+        }
+        self.evict();
+    }
+
+    /// The server answered 304 to a request with `request_headers`: the stored
+    /// body is good, with the headers of the 304 on top.
+    pub(super) fn revalidated(
+        &self,
+        url: &str,
+        request_headers: &[(String, String)],
+        mut entry: CachedResponse,
+        headers: &[(String, String)],
+    ) -> CachedResponse {
+        // Content-Length describes the empty 304 body, not the stored one
+        let headers = headers
+            .iter()
+            .filter(|(name, _)| !name.eq_ignore_ascii_case("Content-Length"));
+        for (name, _) in headers.clone() {
+            entry
+                .headers
+                .retain(|(header, _)| !header.eq_ignore_ascii_case(name));
+        }
+        entry.headers.extend(headers.cloned());
+        entry.vary = varied(&entry.headers, request_headers);
+        entry.expires = SystemTime::now() + freshness(&entry.headers);
+
+        if let Err(err) = self.write(url, &entry) {
+            log::warn!("Failed to cache {}: {}", url, err);
+        }
+        entry
+    }
+
+    fn write(&self, url: &str, entry: &CachedResponse) -> std::io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        let expires = entry
+            .expires
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut data = format!("{}\n{}\n{}\n{}\n", FORMAT, url, entry.url, expires).into_bytes();
+        write_headers(&mut data, &entry.vary);
+        write_headers(&mut data, &entry.headers);
+        data.extend_from_slice(&entry.body);
+
+        // Written aside and moved in place, other workers never see half an entry
+        let path = self.path(url);
+        let temp = path.with_extension(format!("{:?}.tmp", std::thread::current().id()));
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(&data)?;
+        drop(file);
+        fs::rename(&temp, &path)
+    }
+
+    /// Remove the least recently used entries until the cache fits in its size limit.
+    fn evict(&self) {
+        let Ok(dir) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        // Only this cache's entries, leaving alone temporary files being
+        // written and whatever else lives in the directory
+        let mut entries: Vec<_> = dir
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                if !entry.file_name().to_str().is_some_and(is_entry_name) {
+                    return None;
+                }
+                let metadata = entry.metadata().ok()?;
+                Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+            })
+            .collect();
+        let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        if size <= self.max_size {
+            return;
+        }
+
+        entries.sort_by_key(|(modified, _, _)| *modified);
+        for (_, len, path) in entries {
+            if size <= self.max_size {
+                break;
+            }
+            if fs::remove_file(path).is_ok() {
+                size -= len;
+            }
+        }
+    }
+}
+
+/// Headers as `name: value` lines, ending with an empty line.
+fn write_headers(data: &mut Vec<u8>, headers: &[(String, String)]) {
+    for (name, value) in headers {
+        // A line break would end the headers early
+        if !name.contains(['\r', '\n']) && !value.contains(['\r', '\n']) {
+            data.extend_from_slice(format!("{}: {}\n", name, value).as_bytes());
+        }
+    }
+    data.push(b'\n');
+}
+
+fn read_headers(
+    lines: &mut impl Iterator<Item = std::io::Result<String>>,
+) -> Option<Vec<(String, String)>> {
+    let mut headers = vec![];
+    for line in lines {
+        let line = line.ok()?;
+        if line.is_empty() {
+            return Some(headers);
+        }
+        let (name, value) = line.split_once(": ")?;
+        headers.push((name.to_owned(), value.to_owned()));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn directives() {
+        let headers = headers(&[
+            ("Content-Type", "text/plain"),
+            ("cache-control", "public, Max-Age=\"60\""),
+            ("Cache-Control", "no-cache"),
+        ]);
+        assert_eq!(cache_control(&headers, "public"), Some(None));
+        assert_eq!(cache_control(&headers, "max-age"), Some(Some("60")));
+        assert_eq!(cache_control(&headers, "no-cache"), Some(None));
+        assert_eq!(cache_control(&headers, "no-store"), None);
+        assert_eq!(cache_control(&headers, "age"), None);
+    }
+
+    #[test]
+    fn lifetime() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let secs = Duration::from_secs;
+
+        assert_eq!(freshness(&headers(&[])), Duration::ZERO);
+        assert_eq!(
+            freshness(&headers(&[("Cache-Control", "max-age=60")])),
+            secs(60)
+        );
+        assert_eq!(
+            freshness(&headers(&[("Cache-Control", "max-age=60"), ("Age", "20")])),
+            secs(40)
+        );
+        assert_eq!(
+            freshness(&headers(&[("Cache-Control", "max-age=60"), ("Age", "90")])),
+            Duration::ZERO
+        );
+        assert_eq!(
+            freshness(&headers(&[("Cache-Control", "max-age=60, no-cache")])),
+            Duration::ZERO
+        );
+        assert_eq!(
+            freshness(&headers(&[(
+                "Cache-Control",
+                "max-age=18446744073709551615"
+            )])),
+            MAX_FRESHNESS
+        );
+
+        // max-age wins over Expires
+        assert_eq!(
+            freshness(&headers(&[
+                ("Cache-Control", "max-age=5"),
+                ("Date", date),
+                ("Expires", "Sun, 06 Nov 1994 09:49:37 GMT"),
+            ])),
+            secs(5)
+        );
+        assert_eq!(
+            freshness(&headers(&[
+                ("Date", date),
+                ("Expires", "Sun, 06 Nov 1994 09:49:37 GMT")
+            ])),
+            secs(3600)
+        );
+        assert_eq!(
+            freshness(&headers(&[("Date", date), ("Expires", "0")])),
+            Duration::ZERO
+        );
+
+        // A tenth of the age since the last change, up to a day
+        assert_eq!(
+            freshness(&headers(&[
+                ("Date", date),
+                ("Last-Modified", "Sun, 06 Nov 1994 07:49:37 GMT")
+            ])),
+            secs(360)
+        );
+        assert_eq!(
+            freshness(&headers(&[
+                ("Date", date),
+                ("Last-Modified", "Tue, 06 Nov 1984 08:49:37 GMT")
+            ])),
+            MAX_HEURISTIC_FRESHNESS
+        );
+    }
+
+    #[test]
+    fn eviction_spares_other_files() {
+        let dir = std::env::temp_dir().join(format!("quad-net-cache-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let cache = HttpCache::new(dir.clone(), 300);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("notes.txt"), [0; 200]).unwrap();
+        fs::write(dir.join("0123456789abcdef0123456789abcdef.tmp"), [0; 200]).unwrap();
+
+        let headers = headers(&[("Cache-Control", "max-age=60")]);
+        cache.store(
+            "http://example.com/a",
+            &[],
+            "http://example.com/a",
+            &headers,
+            &[0; 60],
+        );
+        cache.store(
+            "http://example.com/b",
+            &[],
+            "http://example.com/b",
+            &headers,
+            &[0; 60],
+        );
+
+        assert!(cache.get("http://example.com/a", &[]).is_none());
+        assert!(cache.get("http://example.com/b", &[]).is_some());
+        assert!(dir.join("notes.txt").exists());
+        assert!(dir.join("0123456789abcdef0123456789abcdef.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn vary() {
+        let dir = std::env::temp_dir().join(format!("quad-net-vary-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let cache = HttpCache::new(dir.clone(), 1000);
+        let url = "http://example.com/text";
+        let french = headers(&[("Accept-Language", "fr"), ("Cookie", "session=1")]);
+        let response = headers(&[
+            ("Cache-Control", "max-age=60"),
+            ("Vary", "accept-language, Cookie"),
+        ]);
+        cache.store(url, &french, url, &response, b"bonjour");
+
+        assert!(cache.get(url, &french).is_some());
+        let english = headers(&[("Accept-Language", "en"), ("Cookie", "session=1")]);
+        assert!(cache.get(url, &english).is_none());
+        assert!(
+            cache
+                .get(url, &headers(&[("Accept-Language", "fr")]))
+                .is_none()
+        );
+        assert!(cache.get(url, &[]).is_none());
+
+        // A 304 keeps the entry for the request that revalidated it
+        let entry = cache.get(url, &french).unwrap();
+        cache.revalidated(url, &english, entry, &headers(&[("ETag", "\"1\"")]));
+        assert!(cache.get(url, &french).is_none());
+        assert!(cache.get(url, &english).is_some());
+
+        // Answers to signed in requests are only kept when public
+        let signed_in = headers(&[("Authorization", "Bearer secret")]);
+        let private = headers(&[("Cache-Control", "max-age=60")]);
+        cache.store(url, &signed_in, url, &private, b"mine");
+        assert!(cache.get(url, &signed_in).is_none());
+        assert!(cache.get(url, &[]).is_none());
+        let public = headers(&[("Cache-Control", "public, max-age=60")]);
+        cache.store(url, &signed_in, url, &public, b"everyone's");
+        assert!(cache.get(url, &[]).is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
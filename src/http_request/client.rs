@@ -0,0 +1,515 @@
+//! Shared http client.
+//!
+//! A client holds the configuration common to many requests: base url,
+//! default headers, timeouts, TLS and proxy settings. Requests made with
+//! `HttpClient::request` start with all of it already set.
+//!
+//! On desktop the client owns a connection pool, so requests to the same host
+//! reuse connections instead of paying for a new TCP and TLS handshake each
+//! time, and a bounded set of worker threads running the requests. Streamed
+//! bodies are read on threads of their own once the response is in, at the
+//! pace of the game. On web the browser does all of that by itself.
+//!
+//! Desktop clients can also keep responses in an on-disk cache, see
+//! `HttpClientBuilder::cache`, and hold requests back to stay under the
+//! server's rate limits, see `HttpClientBuilder::rate_limit`.
+//!
+//! With the `cookies` feature desktop clients can also keep a cookie jar,
+//! sending back the cookies set by earlier responses the way the browser does
+//! on web, see `HttpClientBuilder::cookies`. With the `compression` feature,
+//! desktop requests ask for compressed responses and decompress them.
+
+#[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+use super::HttpError;
+#[cfg(not(target_arch = "wasm32"))]
+use super::RequestState;
+#[cfg(not(target_arch = "wasm32"))]
+use super::cache::HttpCache;
+#[cfg(not(target_arch = "wasm32"))]
+use super::pool::WorkerPool;
+#[cfg(not(target_arch = "wasm32"))]
+use super::proxy_auth::ProxyAuthConnector;
+#[cfg(not(target_arch = "wasm32"))]
+use super::rate_limit::RateLimiter;
+#[cfg(not(target_arch = "wasm32"))]
+use super::tls::TlsConnector;
+#[cfg(not(target_arch = "wasm32"))]
+use super::watchdog::WatchdogConnector;
+use super::{RateLimit, RequestBuilder};
+use crate::proxy::Proxy;
+use crate::tls::TlsConfig;
+#[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+use cookie_store::CookieStore;
+#[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+use std::io::Write;
+use std::path::PathBuf;
+#[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+use std::sync::Mutex;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use ureq::unversioned::resolver::DefaultResolver;
+#[cfg(not(target_arch = "wasm32"))]
+use ureq::unversioned::transport::{
+    ConnectProxyConnector, Connector, SocksConnector, TcpConnector,
+};
+
+/// Requests running at the same time by default, the rest wait in a queue.
+pub const DEFAULT_MAX_CONCURRENCY: usize = 8;
+
+pub struct HttpClientBuilder {
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    max_concurrency: usize,
+    base_url: Option<String>,
+    headers: Vec<(String, String)>,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    max_response_bytes: Option<u64>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    tls: TlsConfig,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    http_proxy: Option<Proxy>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    https_proxy: Option<Proxy>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    cache: Option<(PathBuf, u64)>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    rate_limit: Option<RateLimit>,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    host_rate_limits: Vec<(String, RateLimit)>,
+    #[cfg(feature = "cookies")]
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    cookies: bool,
+}
+
+impl Default for HttpClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HttpClientBuilder {
+    pub fn new() -> HttpClientBuilder {
+        HttpClientBuilder {
+            max_concurrency: DEFAULT_MAX_CONCURRENCY,
+            base_url: None,
+            headers: vec![],
+            timeout: None,
+            connect_timeout: None,
+            read_timeout: None,
+            max_response_bytes: None,
+            tls: TlsConfig::new(),
+            http_proxy: Proxy::from_env("http"),
+            https_proxy: Proxy::from_env("https"),
+            cache: None,
+            rate_limit: None,
+            host_rate_limits: vec![],
+            #[cfg(feature = "cookies")]
+            cookies: false,
+        }
+    }
+
+    /// Maximum number of requests running at the same time on desktop.
+    /// Browsers have their own per-host limits, so this is ignored on web.
+    pub fn max_concurrency(self, max_concurrency: usize) -> HttpClientBuilder {
+        HttpClientBuilder {
+            max_concurrency,
+            ..self
+        }
+    }
+
+    /// Url that relative request urls are appended to.
+    pub fn base_url(self, base_url: &str) -> HttpClientBuilder {
+        HttpClientBuilder {
+            base_url: Some(base_url.to_owned()),
+            ..self
+        }
+    }
+
+    /// Header sent with every request, unless the request sets its own value.
+    pub fn header(mut self, header: &str, value: &str) -> HttpClientBuilder {
+        self.headers.push((header.to_owned(), value.to_owned()));
+
+        Self {
+            headers: self.headers,
+            ..self
+        }
+    }
+
+    /// Shortcut for the `User-Agent` header.
+    /// Browsers do not allow to change it, so it only applies on desktop.
+    pub fn user_agent(self, user_agent: &str) -> HttpClientBuilder {
+        self.header("User-Agent", user_agent)
+    }
+
+    /// Default for `RequestBuilder::timeout`.
+    pub fn timeout(self, timeout: Duration) -> HttpClientBuilder {
+        HttpClientBuilder {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Default for `RequestBuilder::connect_timeout`.
+    pub fn connect_timeout(self, timeout: Duration) -> HttpClientBuilder {
+        HttpClientBuilder {
+            connect_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Default for `RequestBuilder::read_timeout`.
+    pub fn read_timeout(self, timeout: Duration) -> HttpClientBuilder {
+        HttpClientBuilder {
+            read_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Default for `RequestBuilder::max_response_bytes`.
+    pub fn max_response_bytes(self, max_response_bytes: u64) -> HttpClientBuilder {
+        HttpClientBuilder {
+            max_response_bytes: Some(max_response_bytes),
+            ..self
+        }
+    }
+
+    /// Root certificates, pins and client certificate for https.
+    /// Certificates are always up to the browser on web.
+    pub fn tls_config(self, tls: TlsConfig) -> HttpClientBuilder {
+        HttpClientBuilder { tls, ..self }
+    }
+
+    /// Send the requests through `proxy`, or directly with `None`.
+    /// Defaults to `Proxy::from_env` for the scheme of each request.
+    /// The browser picks the proxy on web.
+    pub fn proxy(self, proxy: Option<Proxy>) -> HttpClientBuilder {
+        HttpClientBuilder {
+            http_proxy: proxy.clone(),
+            https_proxy: proxy,
+            ..self
+        }
+    }
+
+    /// Keep GET responses in `dir`, up to `max_size` bytes, dropping the least
+    /// recently used ones past that.
+    ///
+    /// Responses are reused while `Cache-Control` or `Expires` say they are fresh,
+    /// then revalidated with the server through their `ETag` or `Last-Modified`.
+    /// Requests sent with `Cache-Control: no-store` skip the cache, with
+    /// `Cache-Control: no-cache` they are always revalidated.
+    ///
+    /// Desktop only, on web the browser has its own cache.
+    pub fn cache(self, dir: impl Into<PathBuf>, max_size: u64) -> HttpClientBuilder {
+        HttpClientBuilder {
+            cache: Some((dir.into(), max_size)),
+            ..self
+        }
+    }
+
+    /// Limit the requests sent to each host. Requests over the limit wait,
+    /// highest `RequestBuilder::priority` first, instead of being sent and
+    /// rejected with a 429. Retries take their turn too.
+    ///
+    /// With rate limiting on, a 429 or 503 answer with a `Retry-After` header
+    /// also holds back the other requests to its host for that long.
+    ///
+    /// Desktop only.
+    pub fn rate_limit(self, rate_limit: RateLimit) -> HttpClientBuilder {
+        HttpClientBuilder {
+            rate_limit: Some(rate_limit),
+            ..self
+        }
+    }
+
+    /// Limit for `host` only, in place of the one from `rate_limit`.
+    pub fn host_rate_limit(mut self, host: &str, rate_limit: RateLimit) -> HttpClientBuilder {
+        self.host_rate_limits.push((host.to_owned(), rate_limit));
+
+        Self {
+            host_rate_limits: self.host_rate_limits,
+            ..self
+        }
+    }
+
+    /// Keep the cookies set by the responses and send them back with the
+    /// next requests, redirects included. Off by default, so clients sharing
+    /// no session with the server, `HttpClient::shared` among them, never
+    /// send cookies.
+    ///
+    /// Desktop only, the browser keeps the cookies on web.
+    #[cfg(feature = "cookies")]
+    pub fn cookies(self, cookies: bool) -> HttpClientBuilder {
+        HttpClientBuilder { cookies, ..self }
+    }
+
+    pub fn build(self) -> HttpClient {
+        #[cfg(not(target_arch = "wasm32"))]
+        let config = ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .allow_non_standard_methods(true)
+            .build();
+        HttpClient {
+            inner: Arc::new(ClientInner {
+                #[cfg(not(target_arch = "wasm32"))]
+                agent: ureq::Agent::with_parts(
+                    config.clone(),
+                    // ureq's default chain, with our own tunnels for proxies
+                    // with credentials, TLS from `self.tls` and a watchdog
+                    // on the connection under it
+                    ().chain(ProxyAuthConnector {
+                        proxies: self
+                            .http_proxy
+                            .iter()
+                            .chain(&self.https_proxy)
+                            .cloned()
+                            .collect(),
+                        direct: config,
+                    })
+                    .chain(SocksConnector::default())
+                    .chain(ConnectProxyConnector::default())
+                    .chain(TcpConnector::default())
+                    .chain(WatchdogConnector)
+                    .chain(TlsConnector {
+                        config: self.tls.rustls_config(),
+                    }),
+                    DefaultResolver::default(),
+                ),
+                #[cfg(not(target_arch = "wasm32"))]
+                http_proxy: self.http_proxy.as_ref().and_then(Proxy::to_ureq),
+                #[cfg(not(target_arch = "wasm32"))]
+                https_proxy: self.https_proxy.as_ref().and_then(Proxy::to_ureq),
+                #[cfg(not(target_arch = "wasm32"))]
+                pool: WorkerPool::new(self.max_concurrency),
+                #[cfg(not(target_arch = "wasm32"))]
+                rate_limiter: (self.rate_limit.is_some() || !self.host_rate_limits.is_empty())
+                    .then(|| RateLimiter::new(self.rate_limit, self.host_rate_limits)),
+                #[cfg(not(target_arch = "wasm32"))]
+                cache: self
+                    .cache
+                    .map(|(dir, max_size)| HttpCache::new(dir, max_size)),
+                #[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+                cookies: self.cookies.then(|| Mutex::new(CookieStore::default())),
+                base_url: self.base_url,
+                headers: self.headers,
+                timeout: self.timeout,
+                connect_timeout: self.connect_timeout,
+                read_timeout: self.read_timeout,
+                max_response_bytes: self.max_response_bytes,
+            }),
+        }
+    }
+}
+
+struct ClientInner {
+    #[cfg(not(target_arch = "wasm32"))]
+    agent: ureq::Agent,
+    #[cfg(not(target_arch = "wasm32"))]
+    http_proxy: Option<ureq::Proxy>,
+    #[cfg(not(target_arch = "wasm32"))]
+    https_proxy: Option<ureq::Proxy>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pool: Arc<WorkerPool>,
+    #[cfg(not(target_arch = "wasm32"))]
+    rate_limiter: Option<Arc<RateLimiter>>,
+    #[cfg(not(target_arch = "wasm32"))]
+    cache: Option<HttpCache>,
+    #[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+    cookies: Option<Mutex<CookieStore>>,
+    base_url: Option<String>,
+    headers: Vec<(String, String)>,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    max_response_bytes: Option<u64>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Drop for ClientInner {
+    fn drop(&mut self) {
+        self.pool.shutdown();
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.shutdown();
+        }
+    }
+}
+
+/// Cheap to clone, all the clones share the same connections and workers.
+#[derive(Clone)]
+pub struct HttpClient {
+    inner: Arc<ClientInner>,
+}
+
+impl HttpClient {
+    /// The client used by `RequestBuilder::new`.
+    pub fn shared() -> &'static HttpClient {
+        static SHARED: OnceLock<HttpClient> = OnceLock::new();
+        SHARED.get_or_init(|| HttpClientBuilder::new().build())
+    }
+
+    /// Start building a request running on this client, with the client's
+    /// timeouts already set. The client's headers join in on `send`, for
+    /// the names the request did not set.
+    ///
+    /// Urls without a scheme are appended to the base url, if there is one.
+    pub fn request(&self, url: &str) -> RequestBuilder {
+        let inner = &self.inner;
+        let mut request = RequestBuilder::with_client(self.clone(), &self.resolve_url(url));
+
+        if let Some(timeout) = inner.timeout {
+            request = request.timeout(timeout);
+        }
+        if let Some(timeout) = inner.connect_timeout {
+            request = request.connect_timeout(timeout);
+        }
+        if let Some(timeout) = inner.read_timeout {
+            request = request.read_timeout(timeout);
+        }
+        if let Some(max_response_bytes) = inner.max_response_bytes {
+            request = request.max_response_bytes(max_response_bytes);
+        }
+        request
+    }
+
+    /// Headers for the requests that do not set them, added on `RequestBuilder::send`.
+    pub(super) fn headers(&self) -> &[(String, String)] {
+        &self.inner.headers
+    }
+
+    fn resolve_url(&self, url: &str) -> String {
+        match &self.inner.base_url {
+            Some(base_url) if !has_scheme(url) => {
+                format!(
+                    "{}/{}",
+                    base_url.trim_end_matches('/'),
+                    url.trim_start_matches('/')
+                )
+            }
+            _ => url.to_owned(),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(super) fn agent(&self) -> &ureq::Agent {
+        &self.inner.agent
+    }
+
+    /// Proxy for requests to `url`, depending on its scheme.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(super) fn proxy(&self, url: &str) -> Option<ureq::Proxy> {
+        let https = url
+            .get(..8)
+            .is_some_and(|scheme| scheme.eq_ignore_ascii_case("https://"));
+        match https {
+            true => self.inner.https_proxy.clone(),
+            false => self.inner.http_proxy.clone(),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(super) fn cache(&self) -> Option<&HttpCache> {
+        self.inner.cache.as_ref()
+    }
+
+    /// The cookie jar, for clients built with `HttpClientBuilder::cookies`.
+    #[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+    pub(super) fn cookie_jar(&self) -> Option<&Mutex<CookieStore>> {
+        self.inner.cookies.as_ref()
+    }
+
+    /// Write the cookies of this client that have an expiry date to a file,
+    /// for `load_cookies` to bring them back on the next run. Session
+    /// cookies, without one, end with the client and are not written.
+    #[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+    pub fn save_cookies(&self, path: impl AsRef<std::path::Path>) -> Result<(), HttpError> {
+        let jar = self.cookie_jar().ok_or_else(cookies_off)?;
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        cookie_store::serde::json::save(&jar.lock().unwrap(), &mut file)
+            .map_err(std::io::Error::other)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Replace the cookies of this client with the ones saved by `save_cookies`.
+    #[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+    pub fn load_cookies(&self, path: impl AsRef<std::path::Path>) -> Result<(), HttpError> {
+        let jar = self.cookie_jar().ok_or_else(cookies_off)?;
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let cookies = cookie_store::serde::json::load(file).map_err(std::io::Error::other)?;
+        *jar.lock().unwrap() = cookies;
+        Ok(())
+    }
+
+    #[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+    pub fn clear_cookies(&self) {
+        if let Some(jar) = self.cookie_jar() {
+            jar.lock().unwrap().clear();
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(super) fn rate_limiter(&self) -> Option<Arc<RateLimiter>> {
+        self.inner.rate_limiter.clone()
+    }
+
+    /// Run a blocking job for a request to `host` on one of the client's workers,
+    /// once `delay` is over and the rate limit lets it through.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(super) fn spawn(
+        &self,
+        host: Option<&str>,
+        priority: i32,
+        state: Arc<RequestState>,
+        delay: Duration,
+        job: impl FnOnce() + Send + 'static,
+    ) {
+        if !delay.is_zero() {
+            let client = self.clone();
+            let host = host.map(str::to_owned);
+            self.inner.pool.execute_after(delay, move || {
+                client.spawn(host.as_deref(), priority, state, Duration::ZERO, job)
+            });
+            return;
+        }
+        match (&self.inner.rate_limiter, host) {
+            (Some(rate_limiter), Some(host)) => {
+                rate_limiter.execute(&self.inner.pool, host, priority, state, job)
+            }
+            _ => self.inner.pool.execute(job),
+        }
+    }
+}
+
+#[cfg(all(feature = "cookies", not(target_arch = "wasm32")))]
+fn cookies_off() -> std::io::Error {
+    std::io::Error::other("Cookies are off for this client, see HttpClientBuilder::cookies")
+}
+
+/// Whether `url` starts with a scheme like `https://`, as opposed to a path
+/// that may still have a url further in, like `login?next=https://...`.
+fn has_scheme(url: &str) -> bool {
+    url.split_once("://").is_some_and(|(scheme, _)| {
+        scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::has_scheme;
+
+    #[test]
+    fn scheme() {
+        assert!(has_scheme("https://example.com/api"));
+        assert!(has_scheme("HTTP://example.com"));
+        assert!(has_scheme("git+ssh://example.com"));
+        assert!(!has_scheme("login?next=https://example.com"));
+        assert!(!has_scheme("/redirect/https://example.com"));
+        assert!(!has_scheme("users/1"));
+        assert!(!has_scheme("://example.com"));
+        assert!(!has_scheme("1http://example.com"));
+    }
+}
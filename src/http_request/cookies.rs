@@ -0,0 +1,110 @@
+//! Cookie jar of the clients built with `HttpClientBuilder::cookies`.
+//!
+//! The cookies set by a redirect count for the next hop, so those clients
+//! follow redirects themselves instead of leaving it to ureq, one hop at a
+//! time, the way browsers do.
+
+use super::Method;
+use cookie_store::{CookieStore, RawCookie};
+use std::sync::Mutex;
+use url::Url;
+
+/// Value of the `Cookie` header for a request to `url`.
+pub(super) fn header(jar: &Mutex<CookieStore>, url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let jar = jar.lock().unwrap();
+    let value = jar
+        .get_request_values(&url)
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<_>>()
+        .join("; ");
+    (!value.is_empty()).then_some(value)
+}
+
+/// Keep the cookies set by a response to `url`.
+pub(super) fn store<'a>(
+    jar: &Mutex<CookieStore>,
+    url: &str,
+    set_cookies: impl Iterator<Item = &'a str>,
+) {
+    let Ok(url) = Url::parse(url) else {
+        return;
+    };
+    let cookies = set_cookies
+        .filter_map(|cookie| RawCookie::parse(cookie.to_owned()).ok())
+        .collect::<Vec<_>>();
+    jar.lock()
+        .unwrap()
+        .store_response_cookies(cookies.into_iter(), &url);
+}
+
+/// Url and method of the request following a response with `status` and
+/// `location` to a `method` request to `url`, if the response is a redirect.
+///
+/// 307 and 308 keep the method and body, other redirects go on with a GET
+/// without body, unless the request was a HEAD.
+pub(super) fn redirect(
+    url: &str,
+    method: &Method,
+    status: u16,
+    location: Option<&str>,
+) -> Option<(String, Method)> {
+    let method = match status {
+        307 | 308 => method.clone(),
+        301..=303 if *method == Method::Head => Method::Head,
+        301..=303 => Method::Get,
+        _ => return None,
+    };
+    let next = Url::parse(url).ok()?.join(location?).ok()?;
+    matches!(next.scheme(), "http" | "https").then(|| (next.into(), method))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirects() {
+        let url = "https://example.com/a/b?c=d";
+        assert_eq!(
+            redirect(url, &Method::Get, 302, Some("e")),
+            Some(("https://example.com/a/e".to_owned(), Method::Get))
+        );
+        assert_eq!(
+            redirect(url, &Method::Post, 303, Some("/login")),
+            Some(("https://example.com/login".to_owned(), Method::Get))
+        );
+        assert_eq!(
+            redirect(url, &Method::Head, 301, Some("//cdn.example.com/x")),
+            Some(("https://cdn.example.com/x".to_owned(), Method::Head))
+        );
+        assert_eq!(
+            redirect(url, &Method::Post, 307, Some("http://example.org/")),
+            Some(("http://example.org/".to_owned(), Method::Post))
+        );
+        assert_eq!(redirect(url, &Method::Get, 304, Some("/")), None);
+        assert_eq!(redirect(url, &Method::Get, 302, None), None);
+        assert_eq!(
+            redirect(url, &Method::Get, 302, Some("ftp://example.com/")),
+            None
+        );
+    }
+
+    #[test]
+    fn jar() {
+        let jar = Mutex::new(CookieStore::default());
+        store(
+            &jar,
+            "https://example.com/login",
+            ["session=1; Path=/", "theme=dark; Path=/settings"].into_iter(),
+        );
+
+        assert_eq!(
+            header(&jar, "https://example.com/").as_deref(),
+            Some("session=1")
+        );
+        let settings = header(&jar, "https://example.com/settings").unwrap();
+        assert!(settings.contains("session=1") && settings.contains("theme=dark"));
+        assert_eq!(header(&jar, "https://cdn.example.com/"), None);
+    }
+}
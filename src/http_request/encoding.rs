@@ -0,0 +1,118 @@
+//! Response decompression for the `compression` feature, desktop only.
+//! Browsers decompress by themselves on web.
+
+use std::io::{BufRead, BufReader, Read};
+
+/// Sent with every request, unless the request sets its own `Accept-Encoding`.
+pub(super) const ACCEPT_ENCODING: &str = "gzip, deflate, br";
+
+/// Whether `Decoder` knows how to decompress `content_encoding`.
+pub(super) fn is_supported(content_encoding: &str) -> bool {
+    matches!(
+        content_encoding.trim().to_ascii_lowercase().as_str(),
+        "gzip" | "x-gzip" | "deflate" | "br"
+    )
+}
+
+type Body = BufReader<Box<dyn Read + Send>>;
+
+/// Reader decompressing a body. The decompressor starts with the first bytes,
+/// so empty bodies, like the ones of HEAD requests, stay empty instead of
+/// being an invalid compressed stream.
+pub(super) struct Decoder {
+    content_encoding: String,
+    body: Option<Body>,
+    decoder: Option<Box<dyn Read + Send>>,
+}
+
+impl Decoder {
+    pub(super) fn new(content_encoding: &str, body: impl Read + Send + 'static) -> Decoder {
+        Decoder {
+            content_encoding: content_encoding.trim().to_ascii_lowercase(),
+            body: Some(BufReader::new(Box::new(body))),
+            decoder: None,
+        }
+    }
+
+    fn start(&mut self) -> std::io::Result<bool> {
+        let Some(body) = &mut self.body else {
+            return Ok(true);
+        };
+        let start = body.fill_buf()?;
+        if start.is_empty() {
+            return Ok(false);
+        }
+        // Deflate is supposed to come zlib wrapped, but some servers send it
+        // raw, so look for a zlib header like browsers do
+        let zlib = match *start {
+            [cmf, flg, ..] => cmf & 0x0f == 8 && (cmf as u16 * 256 + flg as u16).is_multiple_of(31),
+            _ => true,
+        };
+
+        let body = self.body.take().unwrap();
+        self.decoder = Some(match self.content_encoding.as_str() {
+            "gzip" | "x-gzip" => Box::new(flate2::bufread::MultiGzDecoder::new(body)),
+            "deflate" if zlib => Box::new(flate2::bufread::ZlibDecoder::new(body)),
+            "deflate" => Box::new(flate2::bufread::DeflateDecoder::new(body)),
+            "br" => Box::new(brotli_decompressor::Decompressor::new(body, 4096)),
+            _ => Box::new(body),
+        });
+        Ok(true)
+    }
+}
+
+impl Read for Decoder {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.start()? {
+            return Ok(0);
+        }
+        self.decoder.as_mut().unwrap().read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const TEXT: &[u8] = b"quad-net quad-net quad-net quad-net quad-net quad-net";
+
+    fn decode(content_encoding: &str, body: Vec<u8>) -> Vec<u8> {
+        let mut decoded = vec![];
+        Decoder::new(content_encoding, std::io::Cursor::new(body))
+            .read_to_end(&mut decoded)
+            .unwrap();
+        decoded
+    }
+
+    #[test]
+    fn decoding() {
+        let mut zlib = flate2::write::ZlibEncoder::new(vec![], Compression::default());
+        zlib.write_all(TEXT).unwrap();
+        assert_eq!(decode("deflate", zlib.finish().unwrap()), TEXT);
+
+        let mut raw = flate2::write::DeflateEncoder::new(vec![], Compression::default());
+        raw.write_all(TEXT).unwrap();
+        assert_eq!(decode("Deflate", raw.finish().unwrap()), TEXT);
+
+        // Concatenated members make up a single body
+        let mut gzip = vec![];
+        for _ in 0..2 {
+            let mut member = flate2::write::GzEncoder::new(vec![], Compression::default());
+            member.write_all(TEXT).unwrap();
+            gzip.extend(member.finish().unwrap());
+        }
+        assert_eq!(decode(" gzip ", gzip), [TEXT, TEXT].concat());
+
+        assert_eq!(decode("br", b"\x0b\x02\x80hello\x03".to_vec()), b"hello");
+        assert_eq!(decode("identity", TEXT.to_vec()), TEXT);
+    }
+
+    #[test]
+    fn empty() {
+        for content_encoding in ["gzip", "deflate", "br"] {
+            assert!(decode(content_encoding, vec![]).is_empty());
+        }
+    }
+}
@@ -0,0 +1,191 @@
+struct Part {
+    name: String,
+    filename: Option<String>,
+    content_type: Option<String>,
+    data: Vec<u8>,
+}
+
+/// `multipart/form-data` body, for forms mixing text fields and files.
+///
+/// ```ignore
+/// let form = Multipart::new()
+///     .text("version", "1.2.0")
+///     .file("dump", "crash.dmp", "application/octet-stream", dump);
+/// RequestBuilder::new(url).method(Method::Post).multipart(form).send();
+/// ```
+pub struct Multipart {
+    boundary: String,
+    parts: Vec<Part>,
+}
+
+impl Default for Multipart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Multipart {
+    pub fn new() -> Multipart {
+        Multipart {
+            boundary: boundary(),
+            parts: vec![],
+        }
+    }
+
+    pub fn text(self, name: &str, value: &str) -> Multipart {
+        self.part(Part {
+            name: name.to_owned(),
+            filename: None,
+            content_type: None,
+            data: value.as_bytes().to_vec(),
+        })
+    }
+
+    /// A `content_type` spanning several lines would break the part header,
+    /// it is replaced by `application/octet-stream`.
+    pub fn file(self, name: &str, filename: &str, content_type: &str, data: Vec<u8>) -> Multipart {
+        let content_type = match content_type.contains(['\r', '\n']) {
+            true => "application/octet-stream",
+            false => content_type,
+        };
+        self.part(Part {
+            name: name.to_owned(),
+            filename: Some(filename.to_owned()),
+            content_type: Some(content_type.to_owned()),
+            data,
+        })
+    }
+
+    fn part(mut self, part: Part) -> Multipart {
+        self.parts.push(part);
+        // The boundary can not appear in the data, it would end a part early
+        while self
+            .parts
+            .iter()
+            .any(|part| contains(&part.data, self.boundary.as_bytes()))
+        {
+            self.boundary = boundary();
+        }
+        self
+    }
+
+    /// Value for the `Content-Type` header of the request carrying this form.
+    /// The boundary in it may change as parts are added.
+    pub fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    /// Encoded form, ready to be used as a request body.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = vec![];
+        for part in &self.parts {
+            body.extend_from_slice(format!("--{}\r\n", self.boundary).as_bytes());
+            body.extend_from_slice(
+                format!(
+                    "Content-Disposition: form-data; name=\"{}\"",
+                    escape(&part.name)
+                )
+                .as_bytes(),
+            );
+            if let Some(filename) = &part.filename {
+                body.extend_from_slice(format!("; filename=\"{}\"", escape(filename)).as_bytes());
+            }
+            body.extend_from_slice(b"\r\n");
+            if let Some(content_type) = &part.content_type {
+                body.extend_from_slice(format!("Content-Type: {}\r\n", content_type).as_bytes());
+            }
+            body.extend_from_slice(b"\r\n");
+            body.extend_from_slice(&part.data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+        body
+    }
+}
+
+/// A fresh random boundary, re-rolled by `Multipart::part` when the data holds it.
+fn boundary() -> String {
+    let random = || (super::random() * (1u64 << 52) as f64) as u64;
+    format!("quad-net-{:013x}{:013x}", random(), random())
+}
+
+fn contains(data: &[u8], boundary: &[u8]) -> bool {
+    data.windows(boundary.len())
+        .any(|window| window == boundary)
+}
+
+/// Field names and filenames are quoted strings, encoded the way browsers do.
+fn escape(value: &str) -> String {
+    value
+        .replace('"', "%22")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        let form = Multipart::new().text("version", "1.2.0").file(
+            "dump",
+            "crash \"1\".dmp",
+            "application/octet-stream",
+            vec![0, 1],
+        );
+        let boundary = form.boundary.clone();
+        let expected = [
+            format!("--{}\r\n", boundary).as_bytes(),
+            b"Content-Disposition: form-data; name=\"version\"\r\n\r\n1.2.0\r\n",
+            format!("--{}\r\n", boundary).as_bytes(),
+            b"Content-Disposition: form-data; name=\"dump\"; filename=\"crash %221%22.dmp\"\r\n",
+            b"Content-Type: application/octet-stream\r\n\r\n\x00\x01\r\n",
+            format!("--{}--\r\n", boundary).as_bytes(),
+        ]
+        .concat();
+        assert_eq!(form.to_bytes(), expected);
+        assert_eq!(
+            form.content_type(),
+            format!("multipart/form-data; boundary={}", boundary)
+        );
+    }
+
+    #[test]
+    fn boundaries_differ() {
+        assert_ne!(Multipart::new().boundary, Multipart::new().boundary);
+    }
+
+    #[test]
+    fn boundary_not_in_data() {
+        let form = Multipart {
+            boundary: "quad-net-0".to_owned(),
+            parts: vec![],
+        };
+        let form = form.text("dump", "--quad-net-0\r\nContent-Disposition: injected");
+        assert_ne!(form.boundary, "quad-net-0");
+
+        let boundary = form.boundary.clone();
+        let form = form.file(
+            "log",
+            "log.txt",
+            "text/plain",
+            boundary.clone().into_bytes(),
+        );
+        assert_ne!(form.boundary, boundary);
+        assert!(
+            form.parts
+                .iter()
+                .all(|part| !contains(&part.data, form.boundary.as_bytes()))
+        );
+    }
+
+    #[test]
+    fn content_type_is_one_line() {
+        let form =
+            Multipart::new().file("dump", "crash.dmp", "text/plain\r\nX-Injected: 1", vec![]);
+        let body = String::from_utf8(form.to_bytes()).unwrap();
+        assert!(body.contains("Content-Type: application/octet-stream\r\n"));
+        assert!(!body.contains("X-Injected"));
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Longer delays are as good as never, and keep clear of `Instant` overflows.
+const MAX_DELAY: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+struct Queue {
+    jobs: VecDeque<Job>,
+    /// Jobs waiting for their time to join `jobs`.
+    delayed: Vec<(Instant, Job)>,
+    workers: usize,
+    idle: usize,
+    shutdown: bool,
+}
+
+/// Fixed upper bound of threads running blocking http requests.
+/// Threads are spawned on demand and live until the pool is shut down.
+pub(crate) struct WorkerPool {
+    queue: Mutex<Queue>,
+    available: Condvar,
+    max_workers: usize,
+}
+
+impl WorkerPool {
+    pub(crate) fn new(max_workers: usize) -> Arc<WorkerPool> {
+        Arc::new(WorkerPool {
+            queue: Mutex::new(Queue {
+                jobs: VecDeque::new(),
+                delayed: vec![],
+                workers: 0,
+                idle: 0,
+                shutdown: false,
+            }),
+            available: Condvar::new(),
+            max_workers: max_workers.max(1),
+        })
+    }
+
+    pub(crate) fn execute(self: &Arc<Self>, job: impl FnOnce() + Send + 'static) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.jobs.push_back(Box::new(job));
+        self.wake_worker(&mut queue);
+    }
+
+    /// Queue `job` once `delay` is over, without holding a worker meanwhile.
+    pub(crate) fn execute_after(
+        self: &Arc<Self>,
+        delay: Duration,
+        job: impl FnOnce() + Send + 'static,
+    ) {
+        let mut queue = self.queue.lock().unwrap();
+        let due = Instant::now() + delay.min(MAX_DELAY);
+        queue.delayed.push((due, Box::new(job)));
+        self.wake_worker(&mut queue);
+    }
+
+    /// Let the workers exit once the queued jobs are done.
+    pub(crate) fn shutdown(&self) {
+        self.queue.lock().unwrap().shutdown = true;
+        self.available.notify_all();
+    }
+
+    fn wake_worker(self: &Arc<Self>, queue: &mut Queue) {
+        // Idle workers only leave `idle` once they get the lock back, so a
+        // burst of jobs needs new workers as soon as it outnumbers them
+        let waiting = queue.jobs.len() + usize::from(!queue.delayed.is_empty());
+        if waiting > queue.idle && queue.workers < self.max_workers {
+            queue.workers += 1;
+            let pool = self.clone();
+            std::thread::Builder::new()
+                .name("quad-net-http".to_owned())
+                .spawn(move || pool.work())
+                .expect("Failed to spawn http worker thread");
+        } else {
+            self.available.notify_one();
+        }
+    }
+
+    fn work(self: &Arc<Self>) {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            while let Some(index) = queue.delayed.iter().position(|(due, _)| *due <= now) {
+                let (_, job) = queue.delayed.swap_remove(index);
+                queue.jobs.push_back(job);
+            }
+
+            if let Some(job) = queue.jobs.pop_front() {
+                // Someone else takes over the other jobs that are due and
+                // the watch for the delayed ones
+                if !queue.jobs.is_empty() || !queue.delayed.is_empty() {
+                    self.wake_worker(&mut queue);
+                }
+                drop(queue);
+                job();
+                queue = self.queue.lock().unwrap();
+            } else if queue.shutdown && queue.delayed.is_empty() {
+                queue.workers -= 1;
+                return;
+            } else {
+                queue.idle += 1;
+                let next_due = queue.delayed.iter().map(|(due, _)| *due).min();
+                queue = match next_due {
+                    Some(due) => {
+                        let timeout = due.saturating_duration_since(now);
+                        self.available.wait_timeout(queue, timeout).unwrap().0
+                    }
+                    None => self.available.wait(queue).unwrap(),
+                };
+                queue.idle -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `count` jobs on `pool` that only finish once they all run at the
+    /// same time. They are queued under one lock, like a burst arriving
+    /// before any idle worker had the time to wake up.
+    fn run_together(pool: &Arc<WorkerPool>, count: usize) -> bool {
+        let running = Arc::new((Mutex::new(0), Condvar::new()));
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let mut queue = pool.queue.lock().unwrap();
+        for _ in 0..count {
+            let running = running.clone();
+            let done_tx = done_tx.clone();
+            queue.jobs.push_back(Box::new(move || {
+                let (lock, all_in) = &*running;
+                let mut running = lock.lock().unwrap();
+                *running += 1;
+                all_in.notify_all();
+                let (running, _) = all_in
+                    .wait_timeout_while(running, Duration::from_secs(2), |running| *running < count)
+                    .unwrap();
+                let _ = done_tx.send(*running == count);
+            }));
+            pool.wake_worker(&mut queue);
+        }
+        drop(queue);
+        (0..count).all(|_| done_rx.recv().unwrap())
+    }
+
+    #[test]
+    fn burst_after_idle() {
+        let pool = WorkerPool::new(4);
+        assert!(run_together(&pool, 1));
+        // The first worker is idle by now
+        while pool.queue.lock().unwrap().idle == 0 {
+            std::thread::yield_now();
+        }
+        assert!(run_together(&pool, 4));
+        assert_eq!(pool.queue.lock().unwrap().workers, 4);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn delayed_jobs() {
+        let pool = WorkerPool::new(1);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let start = Instant::now();
+        for delay in [200, 100] {
+            let tx = tx.clone();
+            pool.execute_after(Duration::from_millis(delay), move || {
+                tx.send(delay).unwrap()
+            });
+        }
+        assert_eq!(rx.recv().unwrap(), 100);
+        assert_eq!(rx.recv().unwrap(), 200);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        pool.shutdown();
+    }
+}
@@ -0,0 +1,94 @@
+//! ureq connector opening the `CONNECT` tunnels of http proxies with
+//! credentials. ureq sends the credentials as they appear in the proxy url,
+//! where a `:` in a password, a space or non-ASCII text can not go, so the
+//! tunnel is opened here with the decoded ones from `Proxy` instead.
+
+use crate::proxy::{self, Proxy, ProxyKind};
+use std::io::{Read, Write};
+use ureq::config::Config;
+use ureq::unversioned::transport::{
+    ConnectionDetails, Connector, Either, Transport, TransportAdapter,
+};
+
+#[derive(Debug)]
+pub(super) struct ProxyAuthConnector {
+    /// The proxies requests may be configured with.
+    pub(super) proxies: Vec<Proxy>,
+    /// Client config without a proxy, to connect to the proxy itself.
+    pub(super) direct: Config,
+}
+
+impl<In: Transport> Connector<In> for ProxyAuthConnector {
+    type Out = Either<In, Box<dyn Transport>>;
+
+    fn connect(
+        &self,
+        details: &ConnectionDetails,
+        chained: Option<In>,
+    ) -> Result<Option<Self::Out>, ureq::Error> {
+        if let Some(transport) = chained {
+            return Ok(Some(Either::A(transport)));
+        }
+        let Some(via) = details.config.proxy() else {
+            return Ok(None);
+        };
+        if via.is_no_proxy(details.uri) {
+            return Ok(None);
+        }
+        let Some(proxy) = self.proxies.iter().find(|proxy| proxy.is(via)) else {
+            return Ok(None);
+        };
+        match proxy.kind() {
+            ProxyKind::Http if proxy.has_credentials() => {}
+            // Going without the credentials would only get the connection refused
+            ProxyKind::Socks5 if !proxy.has_url_safe_credentials() => {
+                return Err(std::io::Error::other(
+                    "SOCKS proxy credentials with characters that can not go in an url",
+                )
+                .into());
+            }
+            _ => return Ok(None),
+        }
+
+        let proxy_details = ConnectionDetails {
+            uri: via.uri(),
+            addrs: details
+                .resolver
+                .resolve(via.uri(), &self.direct, details.timeout)?,
+            config: &self.direct,
+            request_level: details.request_level,
+            resolver: details.resolver,
+            now: details.now,
+            timeout: details.timeout,
+            current_time: details.current_time.clone(),
+            run_connector: details.run_connector.clone(),
+        };
+        let mut stream = TransportAdapter::new((details.run_connector)(&proxy_details)?);
+        stream.set_timeout(details.timeout);
+
+        let host = details.uri.host().unwrap_or_default();
+        let port = details
+            .uri
+            .port_u16()
+            .unwrap_or_else(|| match details.uri.scheme_str() {
+                Some("https") => 443,
+                _ => 80,
+            });
+        stream.write_all(proxy.connect_request(host, port).as_bytes())?;
+
+        // Read byte by byte, whatever follows the headers belongs to the tunnel
+        let mut response = vec![];
+        let mut byte = [0];
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() > proxy::MAX_CONNECT_RESPONSE {
+                return Err(std::io::Error::other("Proxy response too long").into());
+            }
+            if stream.read(&mut byte)? == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            response.push(byte[0]);
+        }
+        proxy::check_connect_response(&response)?;
+        Ok(Some(Either::B(stream.into_inner())))
+    }
+}
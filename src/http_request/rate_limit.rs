@@ -0,0 +1,325 @@
+//! Client side rate limiting, desktop only.
+//!
+//! Each host gets a token bucket: a request takes a token, tokens come back
+//! at the configured rate, up to the burst size. Requests finding the bucket
+//! empty wait in a queue, highest `RequestBuilder::priority` first, without
+//! holding one of the client's workers.
+//!
+//! A 429 or 503 response with a `Retry-After` header pauses its host for that long.
+
+use std::time::Duration;
+
+/// How many requests a host gets, see `HttpClientBuilder::rate_limit`.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    per_second: f64,
+    #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+    burst: u32,
+}
+
+impl RateLimit {
+    /// `requests` per `period` on average, all of them allowed at once
+    /// after a quiet period. At least one request goes through per `period`.
+    pub fn new(requests: u32, period: Duration) -> RateLimit {
+        let requests = requests.max(1);
+        RateLimit {
+            per_second: requests as f64 / period.as_secs_f64().max(f64::MIN_POSITIVE),
+            burst: requests,
+        }
+    }
+
+    /// Requests allowed at once after a quiet period, before the rate kicks in.
+    pub fn burst(self, burst: u32) -> RateLimit {
+        RateLimit {
+            burst: burst.max(1),
+            ..self
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(super) use native::RateLimiter;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use super::super::RequestState;
+    use super::super::pool::WorkerPool;
+    use super::RateLimit;
+    use std::collections::{BinaryHeap, HashMap};
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::time::{Duration, Instant};
+
+    /// How often queued requests are checked for cancellation.
+    const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// Longest a host is held back, whatever its rate or `Retry-After` say.
+    const MAX_WAIT: Duration = Duration::from_secs(24 * 60 * 60);
+
+    /// `wait` from `now`, capped at `MAX_WAIT`.
+    fn after(now: Instant, wait: Duration) -> Instant {
+        now.checked_add(wait.min(MAX_WAIT)).unwrap_or(now)
+    }
+
+    struct Pending {
+        priority: i32,
+        /// Order of arrival, first come first served within a priority.
+        seq: u64,
+        state: Arc<RequestState>,
+        job: Box<dyn FnOnce() + Send>,
+    }
+
+    impl PartialEq for Pending {
+        fn eq(&self, other: &Self) -> bool {
+            self.cmp(other).is_eq()
+        }
+    }
+
+    impl Eq for Pending {}
+
+    impl PartialOrd for Pending {
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    // Max heap: highest priority, then lowest seq on top
+    impl Ord for Pending {
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            self.priority
+                .cmp(&other.priority)
+                .then(other.seq.cmp(&self.seq))
+        }
+    }
+
+    struct Bucket {
+        limit: Option<RateLimit>,
+        tokens: f64,
+        refilled: Instant,
+        paused_until: Option<Instant>,
+        pending: BinaryHeap<Pending>,
+    }
+
+    impl Bucket {
+        /// Take a token, or tell when to try again.
+        fn take(&mut self, now: Instant) -> Result<(), Instant> {
+            if let Some(until) = self.paused_until {
+                if now < until {
+                    return Err(until);
+                }
+                self.paused_until = None;
+            }
+            let Some(limit) = self.limit else {
+                return Ok(());
+            };
+
+            let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+            self.refilled = now;
+            if self.tokens >= 1. {
+                self.tokens -= 1.;
+                Ok(())
+            } else {
+                let missing = (1. - self.tokens) / limit.per_second;
+                let wait = Duration::try_from_secs_f64(missing).unwrap_or(MAX_WAIT);
+                Err(after(now, wait))
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Queue {
+        buckets: HashMap<String, Bucket>,
+        seq: u64,
+        dispatcher: bool,
+        shutdown: bool,
+    }
+
+    pub(in super::super) struct RateLimiter {
+        default: Option<RateLimit>,
+        hosts: Vec<(String, RateLimit)>,
+        queue: Mutex<Queue>,
+        changed: Condvar,
+    }
+
+    impl RateLimiter {
+        pub(in super::super) fn new(
+            default: Option<RateLimit>,
+            hosts: Vec<(String, RateLimit)>,
+        ) -> Arc<RateLimiter> {
+            Arc::new(RateLimiter {
+                default,
+                hosts,
+                queue: Mutex::default(),
+                changed: Condvar::new(),
+            })
+        }
+
+        fn bucket<'a>(&self, queue: &'a mut Queue, host: &str) -> &'a mut Bucket {
+            let host = host.to_ascii_lowercase();
+            let limit = self
+                .hosts
+                .iter()
+                .find(|(limited, _)| limited.eq_ignore_ascii_case(&host))
+                .map(|(_, limit)| *limit)
+                .or(self.default);
+            queue.buckets.entry(host).or_insert_with(|| Bucket {
+                limit,
+                tokens: limit.map_or(0., |limit| limit.burst as f64),
+                refilled: Instant::now(),
+                paused_until: None,
+                pending: BinaryHeap::new(),
+            })
+        }
+
+        /// Run `job` on `pool` once `host` has a token for it.
+        pub(in super::super) fn execute(
+            self: &Arc<Self>,
+            pool: &Arc<WorkerPool>,
+            host: &str,
+            priority: i32,
+            state: Arc<RequestState>,
+            job: impl FnOnce() + Send + 'static,
+        ) {
+            let mut queue = self.queue.lock().unwrap();
+            queue.seq += 1;
+            let seq = queue.seq;
+            self.bucket(&mut queue, host).pending.push(Pending {
+                priority,
+                seq,
+                state,
+                job: Box::new(job),
+            });
+
+            if !queue.dispatcher {
+                queue.dispatcher = true;
+                let limiter = self.clone();
+                let pool = pool.clone();
+                std::thread::Builder::new()
+                    .name("quad-net-rate-limit".to_owned())
+                    .spawn(move || limiter.dispatch(&pool))
+                    .expect("Failed to spawn http rate limit thread");
+            }
+            self.changed.notify_all();
+        }
+
+        /// Hold back every request to `host` for `duration`, as asked by a `Retry-After`.
+        pub(in super::super) fn pause(&self, host: &str, duration: Duration) {
+            let until = after(Instant::now(), duration);
+            let mut queue = self.queue.lock().unwrap();
+            let bucket = self.bucket(&mut queue, host);
+            bucket.paused_until = bucket.paused_until.max(Some(until));
+        }
+
+        /// Let the dispatcher exit, the client is gone so nothing is queued anymore.
+        pub(in super::super) fn shutdown(&self) {
+            self.queue.lock().unwrap().shutdown = true;
+            self.changed.notify_all();
+        }
+
+        fn dispatch(&self, pool: &Arc<WorkerPool>) {
+            let mut queue = self.queue.lock().unwrap();
+            while !queue.shutdown {
+                let now = Instant::now();
+                let mut wake_at = None;
+                for bucket in queue.buckets.values_mut() {
+                    while let Some(pending) = bucket.pending.peek() {
+                        // Cancelled requests finish right away, without a token
+                        let ready = match pending.state.is_cancelled() {
+                            true => Ok(()),
+                            false => bucket.take(now),
+                        };
+                        if let Err(until) = ready {
+                            wake_at =
+                                Some(wake_at.map_or(until, |wake_at: Instant| wake_at.min(until)));
+                            break;
+                        }
+                        let pending = bucket.pending.pop().unwrap();
+                        pool.execute(pending.job);
+                    }
+                }
+                // Unlimited hosts only need a bucket while something is queued or paused
+                queue.buckets.retain(|_, bucket| {
+                    bucket.limit.is_some()
+                        || bucket.paused_until.is_some()
+                        || !bucket.pending.is_empty()
+                });
+
+                queue = match wake_at {
+                    Some(wake_at) => {
+                        let timeout = wake_at
+                            .saturating_duration_since(Instant::now())
+                            .min(CANCEL_CHECK_INTERVAL);
+                        self.changed.wait_timeout(queue, timeout).unwrap().0
+                    }
+                    None => self.changed.wait(queue).unwrap(),
+                };
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn bucket(limit: RateLimit, now: Instant) -> Bucket {
+            Bucket {
+                limit: Some(limit),
+                tokens: limit.burst as f64,
+                refilled: now,
+                paused_until: None,
+                pending: BinaryHeap::new(),
+            }
+        }
+
+        #[test]
+        fn burst_then_rate() {
+            let now = Instant::now();
+            let limit = RateLimit::new(2, Duration::from_secs(1)).burst(3);
+            let mut bucket = bucket(limit, now);
+            for _ in 0..3 {
+                assert_eq!(bucket.take(now), Ok(()));
+            }
+            let retry = bucket.take(now).unwrap_err();
+            assert_eq!(retry - now, Duration::from_millis(500));
+
+            let later = now + Duration::from_millis(500);
+            assert_eq!(bucket.take(later), Ok(()));
+            assert!(bucket.take(later).is_err());
+
+            // Refills stop at the burst size
+            let much_later = later + Duration::from_secs(60);
+            for _ in 0..3 {
+                assert_eq!(bucket.take(much_later), Ok(()));
+            }
+            assert!(bucket.take(much_later).is_err());
+        }
+
+        #[test]
+        fn degenerate_limits() {
+            let now = Instant::now();
+            let mut zero = bucket(RateLimit::new(0, Duration::from_secs(1)), now);
+            assert_eq!(zero.take(now), Ok(()));
+            assert_eq!(zero.take(now).unwrap_err() - now, Duration::from_secs(1));
+
+            let mut endless = bucket(RateLimit::new(1, Duration::MAX), now);
+            assert_eq!(endless.take(now), Ok(()));
+            assert_eq!(endless.take(now).unwrap_err() - now, MAX_WAIT);
+        }
+
+        #[test]
+        fn pause() {
+            let limiter = RateLimiter::new(None, vec![]);
+            limiter.pause("example.com", Duration::MAX);
+            limiter.pause("example.com", Duration::from_secs(1));
+
+            let now = Instant::now();
+            let mut queue = limiter.queue.lock().unwrap();
+            let bucket = limiter.bucket(&mut queue, "Example.com");
+            let until = bucket.take(now).unwrap_err();
+            assert!(until - now <= MAX_WAIT);
+            assert!(until - now > MAX_WAIT - Duration::from_secs(60));
+            assert_eq!(bucket.take(until), Ok(()));
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use super::{HttpError, Method, Response};
+use std::time::Duration;
+
+/// When and how often a failed request is sent again.
+///
+/// Retries happen inside the `Request`, the game keeps polling the same
+/// `Request` and only sees the outcome of the last attempt.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: f32,
+    retry_statuses: Vec<u16>,
+    retry_network_errors: bool,
+    retry_timeouts: bool,
+    idempotent_only: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(3)
+    }
+}
+
+impl RetryPolicy {
+    /// `max_attempts` includes the first try, so 3 means up to 2 retries.
+    ///
+    /// By default network errors, timeouts and 408, 429, 500, 502, 503 and 504
+    /// responses are retried, starting with a 500ms delay that doubles
+    /// every attempt up to 30s.
+    pub fn new(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            jitter: 0.5,
+            retry_statuses: vec![408, 429, 500, 502, 503, 504],
+            retry_network_errors: true,
+            retry_timeouts: true,
+            idempotent_only: true,
+        }
+    }
+
+    /// Delay before the first retry, doubled for every following one, but
+    /// never longer than `max`.
+    pub fn backoff(self, initial: Duration, max: Duration) -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: initial,
+            max_backoff: max,
+            ..self
+        }
+    }
+
+    /// Part of each delay that is random, so clients that failed together
+    /// do not all come back at the same moment.
+    /// 0 keeps the delays exact, 1 picks anything between zero and the full delay.
+    pub fn jitter(self, jitter: f32) -> RetryPolicy {
+        RetryPolicy {
+            jitter: jitter.clamp(0., 1.),
+            ..self
+        }
+    }
+
+    /// Response statuses worth another attempt.
+    pub fn retry_statuses(self, statuses: &[u16]) -> RetryPolicy {
+        RetryPolicy {
+            retry_statuses: statuses.to_vec(),
+            ..self
+        }
+    }
+
+    pub fn retry_network_errors(self, retry_network_errors: bool) -> RetryPolicy {
+        RetryPolicy {
+            retry_network_errors,
+            ..self
+        }
+    }
+
+    pub fn retry_timeouts(self, retry_timeouts: bool) -> RetryPolicy {
+        RetryPolicy {
+            retry_timeouts,
+            ..self
+        }
+    }
+
+    /// Only retry methods that are safe to send twice: GET, HEAD, OPTIONS,
+    /// PUT and DELETE. On by default, turn it off for POST endpoints known
+    /// to be idempotent.
+    pub fn idempotent_only(self, idempotent_only: bool) -> RetryPolicy {
+        RetryPolicy {
+            idempotent_only,
+            ..self
+        }
+    }
+
+    /// How long to wait before attempt number `attempt + 1`,
+    /// or `None` if the outcome of `attempt` is final.
+    pub(super) fn next_delay(
+        &self,
+        method: &Method,
+        attempt: u32,
+        outcome: Result<&Response, &HttpError>,
+    ) -> Option<Duration> {
+        if attempt >= self.max_attempts || (self.idempotent_only && !method.is_idempotent()) {
+            return None;
+        }
+
+        let retry_after = match outcome {
+            Ok(response) if self.retry_statuses.contains(&response.status) => retry_after(response),
+            Err(HttpError::Timeout) if self.retry_timeouts => None,
+            Err(error) if self.retry_network_errors && error.is_network_error() => None,
+            _ => return None,
+        };
+
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_backoff);
+        let factor = 1. - self.jitter as f64 * super::random();
+        // Through `try_` as rounding may push the longest durations past `Duration::MAX`
+        let backoff =
+            Duration::try_from_secs_f64(backoff.as_secs_f64() * factor).unwrap_or(backoff);
+
+        match retry_after {
+            // The server wants more patience than the policy has
+            Some(retry_after) if retry_after > self.max_backoff => None,
+            Some(retry_after) => Some(retry_after.max(backoff)),
+            None => Some(backoff),
+        }
+    }
+}
+
+/// How long the server asks to wait with `Retry-After`, given either
+/// in seconds or as a date.
+pub(super) fn retry_after(response: &Response) -> Option<Duration> {
+    let retry_after = response.header("Retry-After")?.trim();
+    match retry_after.parse() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        #[cfg(not(target_arch = "wasm32"))]
+        Err(_) => httpdate::parse_http_date(retry_after).ok().map(|date| {
+            date.duration_since(std::time::SystemTime::now())
+                .unwrap_or_default()
+        }),
+        // There is no clock to compare the date with on web
+        #[cfg(target_arch = "wasm32")]
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_request::ResponsePayload;
+
+    fn response(status: u16, headers: &[(&str, &str)]) -> Response {
+        Response {
+            status,
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            url: "http://example.com".to_owned(),
+            redirects: vec![],
+            stale: false,
+            body: ResponsePayload::Text(String::new()),
+        }
+    }
+
+    fn exact(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy::new(max_attempts)
+            .jitter(0.)
+            .backoff(Duration::from_secs(1), Duration::from_secs(10))
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let retry = exact(10);
+        let unavailable = response(503, &[]);
+        let delays: Vec<_> = (1..7)
+            .map(|attempt| retry.next_delay(&Method::Get, attempt, Ok(&unavailable)))
+            .collect();
+        let secs = |secs: &[u64]| {
+            secs.iter()
+                .map(|secs| Some(Duration::from_secs(*secs)))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(delays, secs(&[1, 2, 4, 8, 10, 10]));
+    }
+
+    #[test]
+    fn stops_after_max_attempts() {
+        let retry = exact(3);
+        let unavailable = response(503, &[]);
+        assert!(
+            retry
+                .next_delay(&Method::Get, 2, Ok(&unavailable))
+                .is_some()
+        );
+        assert_eq!(retry.next_delay(&Method::Get, 3, Ok(&unavailable)), None);
+    }
+
+    #[test]
+    fn what_is_retried() {
+        let retry = exact(3);
+        let status = |status| retry.next_delay(&Method::Get, 1, Ok(&response(status, &[])));
+        let error = |error| retry.next_delay(&Method::Get, 1, Err(&error));
+        assert!(status(502).is_some());
+        assert!(error(HttpError::Timeout).is_some());
+        assert!(error(HttpError::NetworkError).is_some());
+        assert_eq!(status(200), None);
+        assert_eq!(status(404), None);
+        assert_eq!(error(HttpError::Aborted), None);
+
+        let retry = exact(3).retry_timeouts(false).retry_network_errors(false);
+        assert_eq!(
+            retry.next_delay(&Method::Get, 1, Err(&HttpError::Timeout)),
+            None
+        );
+        assert_eq!(
+            retry.next_delay(&Method::Get, 1, Err(&HttpError::NetworkError)),
+            None
+        );
+    }
+
+    #[test]
+    fn idempotent_only() {
+        let unavailable = response(503, &[]);
+        let retry = exact(3);
+        assert_eq!(retry.next_delay(&Method::Post, 1, Ok(&unavailable)), None);
+        assert!(
+            retry
+                .next_delay(&Method::Put, 1, Ok(&unavailable))
+                .is_some()
+        );
+        let retry = exact(3).idempotent_only(false);
+        assert!(
+            retry
+                .next_delay(&Method::Post, 1, Ok(&unavailable))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn jitter_stays_within_the_backoff() {
+        let retry = exact(3).jitter(1.);
+        let unavailable = response(503, &[]);
+        for _ in 0..100 {
+            let delay = retry.next_delay(&Method::Get, 2, Ok(&unavailable)).unwrap();
+            assert!(delay <= Duration::from_secs(2));
+        }
+    }
+
+    #[test]
+    fn longest_backoff() {
+        let retry = RetryPolicy::new(u32::MAX).backoff(Duration::MAX, Duration::MAX);
+        let unavailable = response(503, &[]);
+        for attempt in [1, 2, 64, 1000] {
+            assert!(
+                retry
+                    .next_delay(&Method::Get, attempt, Ok(&unavailable))
+                    .is_some()
+            );
+        }
+        let retry = retry.jitter(0.);
+        let delay = retry.next_delay(&Method::Get, 1000, Ok(&unavailable));
+        assert_eq!(delay, Some(Duration::MAX));
+    }
+
+    #[test]
+    fn honors_retry_after() {
+        let retry = exact(3);
+        let later = response(429, &[("Retry-After", "5")]);
+        let soon = response(429, &[("Retry-After", "0")]);
+        let too_late = response(429, &[("Retry-After", "60")]);
+        let delay = |response| retry.next_delay(&Method::Get, 1, Ok(response));
+        assert_eq!(delay(&later), Some(Duration::from_secs(5)));
+        assert_eq!(delay(&soon), Some(Duration::from_secs(1)));
+        assert_eq!(delay(&too_late), None);
+    }
+
+    #[test]
+    fn parse_retry_after() {
+        let parse = |value: &str| retry_after(&response(503, &[("retry-after", value)]));
+        assert_eq!(parse(" 120 "), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse("18446744073709551615"),
+            Some(Duration::from_secs(u64::MAX))
+        );
+        assert_eq!(parse("soon"), None);
+        assert_eq!(parse("-1"), None);
+        assert_eq!(parse("Wed, 21 Oct 2015 07:28:00 GMT"), Some(Duration::ZERO));
+        let in_a_minute = std::time::SystemTime::now() + Duration::from_secs(60);
+        let date = parse(&httpdate::fmt_http_date(in_a_minute)).unwrap();
+        assert!(date > Duration::from_secs(55) && date <= Duration::from_secs(60));
+        assert_eq!(retry_after(&response(503, &[])), None);
+    }
+}
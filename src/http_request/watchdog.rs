@@ -0,0 +1,106 @@
+//! ureq connector keeping an eye on the waits for the server, for what ureq's
+//! own timeouts can not do: its body timeout is a budget for the whole body,
+//! while `RequestBuilder::read_timeout` is the longest silence allowed between
+//! two pieces of it, like on web. Waits also end as soon as the request gets
+//! cancelled, instead of when the server finally answers.
+
+use super::RequestState;
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use ureq::Timeout;
+use ureq::unversioned::transport::{Buffers, ConnectionDetails, Connector, NextTimeout, Transport};
+
+/// How often a wait for the server checks whether the request was cancelled.
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+thread_local! {
+    /// Request run by this thread.
+    static WATCHED: RefCell<Option<Arc<RequestState>>> = const { RefCell::new(None) };
+    /// Longest silence allowed from the server by the read running on this thread.
+    static IDLE_TIMEOUT: Cell<Option<Duration>> = const { Cell::new(None) };
+}
+
+/// Run `run`, stopping its waits for the server once `state` is cancelled.
+pub(super) fn watch<T>(state: &Arc<RequestState>, run: impl FnOnce() -> T) -> T {
+    let previous = WATCHED.replace(Some(state.clone()));
+    let result = run();
+    WATCHED.set(previous);
+    result
+}
+
+/// Run `read`, failing with a timeout once the server stays silent for `idle_timeout`.
+pub(super) fn idle_timeout<T>(idle_timeout: Option<Duration>, read: impl FnOnce() -> T) -> T {
+    let previous = IDLE_TIMEOUT.replace(idle_timeout);
+    let result = read();
+    IDLE_TIMEOUT.set(previous);
+    result
+}
+
+#[derive(Debug)]
+pub(super) struct WatchdogConnector;
+
+impl<In: Transport> Connector<In> for WatchdogConnector {
+    type Out = WatchdogTransport<In>;
+
+    fn connect(
+        &self,
+        _details: &ConnectionDetails,
+        chained: Option<In>,
+    ) -> Result<Option<Self::Out>, ureq::Error> {
+        Ok(chained.map(|inner| WatchdogTransport { inner }))
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct WatchdogTransport<T> {
+    inner: T,
+}
+
+impl<T: Transport> Transport for WatchdogTransport<T> {
+    fn buffers(&mut self) -> &mut dyn Buffers {
+        self.inner.buffers()
+    }
+
+    fn transmit_output(&mut self, amount: usize, timeout: NextTimeout) -> Result<(), ureq::Error> {
+        self.inner.transmit_output(amount, timeout)
+    }
+
+    fn await_input(&mut self, timeout: NextTimeout) -> Result<bool, ureq::Error> {
+        let timeout = match IDLE_TIMEOUT.get() {
+            Some(idle_timeout) if idle_timeout < *timeout.after => NextTimeout {
+                after: idle_timeout.into(),
+                reason: Timeout::RecvBody,
+            },
+            _ => timeout,
+        };
+        let Some(state) = WATCHED.with_borrow(Clone::clone) else {
+            return self.inner.await_input(timeout);
+        };
+
+        // Waited for in slices, to look at the request in between
+        let start = Instant::now();
+        loop {
+            if state.is_cancelled() {
+                return Err(std::io::Error::other("Http request cancelled").into());
+            }
+            let left = timeout.after.saturating_sub(start.elapsed());
+            let slice = NextTimeout {
+                after: left.min(CANCEL_CHECK_INTERVAL).into(),
+                reason: timeout.reason,
+            };
+            match self.inner.await_input(slice) {
+                Err(ureq::Error::Timeout(_)) if left > CANCEL_CHECK_INTERVAL => continue,
+                result => return result,
+            }
+        }
+    }
+
+    fn is_open(&mut self) -> bool {
+        self.inner.is_open()
+    }
+
+    fn is_tls(&self) -> bool {
+        self.inner.is_tls()
+    }
+}
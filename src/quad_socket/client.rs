@@ -4,6 +4,8 @@ mod websocket;
 use crate::web_socket::js_web_socket as websocket;
 
 use crate::error::Error;
+use crate::proxy::Proxy;
+use crate::tls::TlsConfig;
 
 pub struct QuadSocket {
     #[cfg(not(target_arch = "wasm32"))]
@@ -48,15 +50,52 @@ impl QuadSocket {
             self.web_socket.try_recv()
         }
     }
+
+    /// Wait for the next message, `None` once the socket is closed and
+    /// every message was received.
+    ///
+    /// Works with any executor, macroquad coroutines included.
+    pub async fn recv(&mut self) -> Option<IncomingSocketMessage> {
+        std::future::poll_fn(|cx| {
+            #[cfg(not(target_arch = "wasm32"))]
+            {
+                self.tcp_socket.poll_recv(cx)
+            }
+
+            #[cfg(target_arch = "wasm32")]
+            {
+                self.web_socket.poll_recv(cx)
+            }
+        })
+        .await
+    }
 }
 
 impl QuadSocket {
-    pub fn connect(addr: impl Into<String>, disable_cert_verification: bool) -> QuadSocket {
+    /// Connect through the proxy from the environment for the scheme of
+    /// `addr`, if there is one, see `Proxy::from_env`.
+    pub fn connect(addr: impl Into<String>, tls: TlsConfig) -> QuadSocket {
+        let addr = addr.into();
+        let scheme = addr.split_once("://").map_or("ws", |(scheme, _)| scheme);
+        let proxy = Proxy::from_env(scheme);
+        Self::connect_with_proxy(addr, tls, proxy)
+    }
+
+    /// Connect through `proxy`, or directly with `None`.
+    /// Desktop only, on web the browser picks the proxy.
+    pub fn connect_with_proxy(
+        addr: impl Into<String>,
+        tls: TlsConfig,
+        proxy: Option<Proxy>,
+    ) -> QuadSocket {
         QuadSocket {
             #[cfg(not(target_arch = "wasm32"))]
-            tcp_socket: websocket::WebSocket::connect(addr, disable_cert_verification),
+            tcp_socket: websocket::WebSocket::connect(addr, tls, proxy),
             #[cfg(target_arch = "wasm32")]
-            web_socket: websocket::WebSocket::connect(addr),
+            web_socket: {
+                let _ = (tls, proxy);
+                websocket::WebSocket::connect(addr)
+            },
         }
     }
 }
@@ -1,19 +1,20 @@
 use crate::error::Error;
+use crate::proxy::Proxy;
 use crate::quad_socket::client::{IncomingSocketMessage, OutgoingSocketMessage};
+use crate::tls::TlsConfig;
 use futures::{SinkExt, StreamExt};
 use log::error;
-use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
-use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
-use rustls::{DigitallySignedStruct, SignatureScheme};
 use std::sync::{
     Arc,
     atomic::{AtomicBool, Ordering},
 };
+use std::task::{Context, Poll};
 use std::time::SystemTime;
 use tokio::runtime::Runtime;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
 use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::{Connector, connect_async, connect_async_tls_with_config};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::{Connector, client_async_tls_with_config, connect_async_tls_with_config};
 
 fn send_closed_once(tx: &UnboundedSender<IncomingSocketMessage>, closed: &AtomicBool) {
     if !closed.swap(true, Ordering::AcqRel) {
@@ -25,6 +26,7 @@ pub struct WebSocket {
     _runtime: Runtime, // Need this here to keep it alive for the duration of the socket otherwise it kills all tasks
     rx: UnboundedReceiver<IncomingSocketMessage>,
     tx: UnboundedSender<OutgoingSocketMessage>,
+    closed: bool,
 }
 
 impl WebSocket {
@@ -33,7 +35,21 @@ impl WebSocket {
     }
 
     pub fn try_recv(&mut self) -> Option<IncomingSocketMessage> {
-        self.rx.try_recv().ok()
+        let msg = self.rx.try_recv().ok();
+        self.closed |= matches!(msg, Some(IncomingSocketMessage::Closed));
+        msg
+    }
+
+    /// `None` once the socket is closed and every message was received.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<IncomingSocketMessage>> {
+        match self.rx.poll_recv(cx) {
+            Poll::Pending if self.closed => Poll::Ready(None),
+            Poll::Ready(Some(IncomingSocketMessage::Closed)) => {
+                self.closed = true;
+                Poll::Ready(Some(IncomingSocketMessage::Closed))
+            }
+            poll => poll,
+        }
     }
 
     pub fn close(&mut self) {
@@ -42,7 +58,7 @@ impl WebSocket {
 }
 
 impl WebSocket {
-    pub fn connect(addr: impl Into<String>, disable_cert_verification: bool) -> WebSocket {
+    pub fn connect(addr: impl Into<String>, tls: TlsConfig, proxy: Option<Proxy>) -> WebSocket {
         let (incoming_sock_msg_tx, incoming_sock_msg_rx) = unbounded_channel();
         let (outgoing_sock_msg_tx, mut outgoing_sock_msg_rx) = unbounded_channel();
         let closed = Arc::new(AtomicBool::new(false));
@@ -55,21 +71,11 @@ impl WebSocket {
             .unwrap();
 
         runtime.spawn(async move {
-            // Create a connector that disables certificate verification if requested
-            let socket = if disable_cert_verification {
-                // Create a TLS connector that disables certificate verification
-                let tls_config = {
-                    let config = rustls::ClientConfig::builder()
-                        .dangerous()
-                        .with_custom_certificate_verifier(Arc::new(NoCertificateVerification {}))
-                        .with_no_client_auth();
-                    Arc::new(config)
-                };
-                let connector = Connector::Rustls(tls_config);
-                // Connect with the custom connector
-                connect_async_tls_with_config(addr, None, true, Some(connector)).await
-            } else {
-                connect_async(addr).await
+            let connector = Some(Connector::Rustls(tls.rustls_config()));
+
+            let socket = match proxy {
+                Some(proxy) => connect_through(&proxy, addr, connector).await,
+                None => connect_async_tls_with_config(addr, None, true, connector).await,
             };
 
             if let Err(e) = socket {
@@ -165,63 +171,42 @@ impl WebSocket {
             _runtime: runtime,
             rx: incoming_sock_msg_rx,
             tx: outgoing_sock_msg_tx,
+            closed: false,
         }
     }
 }
 
-// Add this struct for rustls certificate verification disabling
-#[derive(Debug)]
-struct NoCertificateVerification;
-
-impl ServerCertVerifier for NoCertificateVerification {
-    fn verify_server_cert(
-        &self,
-        _end_entity: &CertificateDer<'_>,
-        _intermediates: &[CertificateDer<'_>],
-        _server_name: &ServerName<'_>,
-        _ocsp_response: &[u8],
-        _now: UnixTime,
-    ) -> Result<ServerCertVerified, rustls::Error> {
-        // For this example, we will skip all verification
-        // In a real application, you should implement proper certificate verification
-        Ok(ServerCertVerified::assertion())
-    }
-
-    fn verify_tls12_signature(
-        &self,
-        _message: &[u8],
-        _cert: &CertificateDer<'_>,
-        _dss: &DigitallySignedStruct,
-    ) -> Result<HandshakeSignatureValid, rustls::Error> {
-        // For TLS 1.2, we can skip the verification
-        Ok(HandshakeSignatureValid::assertion())
-    }
-
-    fn verify_tls13_signature(
-        &self,
-        _message: &[u8],
-        _cert: &CertificateDer<'_>,
-        _dss: &DigitallySignedStruct,
-    ) -> Result<HandshakeSignatureValid, rustls::Error> {
-        // For TLS 1.3, we can skip the verification
-        Ok(HandshakeSignatureValid::assertion())
+/// Open the websocket over a tunnel through `proxy`, unless the host bypasses it.
+async fn connect_through(
+    proxy: &Proxy,
+    addr: String,
+    connector: Option<Connector>,
+) -> Result<
+    (
+        tokio_tungstenite::WebSocketStream<
+            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+        >,
+        tokio_tungstenite::tungstenite::handshake::client::Response,
+    ),
+    tokio_tungstenite::tungstenite::Error,
+> {
+    let request = addr.into_client_request()?;
+    let uri = request.uri();
+    let host = uri.host().unwrap_or_default().to_owned();
+    if proxy.is_bypassed(&host) {
+        return connect_async_tls_with_config(request, None, true, connector).await;
     }
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("wss") {
+            443
+        } else {
+            80
+        });
 
-    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
-        vec![
-            SignatureScheme::RSA_PKCS1_SHA256,
-            SignatureScheme::RSA_PKCS1_SHA384,
-            SignatureScheme::RSA_PKCS1_SHA512,
-            SignatureScheme::RSA_PSS_SHA256,
-            SignatureScheme::RSA_PSS_SHA384,
-            SignatureScheme::RSA_PSS_SHA512,
-            SignatureScheme::ECDSA_NISTP256_SHA256,
-            SignatureScheme::ECDSA_NISTP384_SHA384,
-            SignatureScheme::ECDSA_NISTP521_SHA512,
-            SignatureScheme::ED25519,
-            SignatureScheme::ED448,
-        ]
-    }
+    let stream = proxy.connect(&host, port).await?;
+    let _ = stream.set_nodelay(true);
+    client_async_tls_with_config(request, stream, None, connector).await
 }
 
 pub fn current_time_millis() -> u64 {
@@ -0,0 +1,287 @@
+//! TLS settings shared by http requests and sockets.
+//!
+//! Desktop only: on web certificates are up to the browser and these are ignored.
+
+use crate::error::Error;
+use base64::Engine;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::sync::Arc;
+
+/// Certificates are checked against the usual web root CAs by default.
+///
+/// ```ignore
+/// let tls = TlsConfig::new()
+///     .add_root_certs_pem(include_bytes!("staging-ca.pem"))?
+///     .pin_public_key("r/mIkG3eEpVdm+u/ko/cwxzOMo1bk4TyHIlByibiA5E=")?;
+/// ```
+#[derive(Clone, Default)]
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+pub struct TlsConfig {
+    root_certs: Vec<CertificateDer<'static>>,
+    pinned_keys: Vec<[u8; 32]>,
+    client_cert: Option<(Vec<CertificateDer<'static>>, Arc<PrivateKeyDer<'static>>)>,
+    disable_verification: bool,
+}
+
+impl std::fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("root_certs", &self.root_certs.len())
+            .field("pinned_keys", &self.pinned_keys.len())
+            .field("client_cert", &self.client_cert.is_some())
+            .field("disable_verification", &self.disable_verification)
+            .finish()
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::from(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        message,
+    ))
+}
+
+impl TlsConfig {
+    pub fn new() -> TlsConfig {
+        TlsConfig::default()
+    }
+
+    /// Also trust the CA certificates in `pem`, like a staging CA.
+    pub fn add_root_certs_pem(mut self, pem: &[u8]) -> Result<TlsConfig, Error> {
+        let certs = CertificateDer::pem_slice_iter(pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid("Invalid root certificate PEM"))?;
+        if certs.is_empty() {
+            return Err(invalid("No certificate in root certificate PEM"));
+        }
+        self.root_certs.extend(certs);
+        Ok(self)
+    }
+
+    /// Only accept servers whose certificate chain has a public key with this
+    /// base64 SHA-256 hash of its SubjectPublicKeyInfo, on top of the usual checks.
+    /// Call again to allow several keys, for example the current and the next one.
+    ///
+    /// The hash of a certificate's key is given by
+    /// `openssl x509 -in cert.pem -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`
+    pub fn pin_public_key(mut self, spki_sha256: &str) -> Result<TlsConfig, Error> {
+        // curl's --pinnedpubkey syntax
+        let spki_sha256 = spki_sha256.trim().trim_start_matches("sha256//");
+        let hash = base64::engine::general_purpose::STANDARD
+            .decode(spki_sha256)
+            .ok()
+            .and_then(|hash| <[u8; 32]>::try_from(hash).ok())
+            .ok_or_else(|| invalid("Public key pin is not a base64 SHA-256 hash"))?;
+        self.pinned_keys.push(hash);
+        Ok(self)
+    }
+
+    /// Client certificate for servers asking for mutual TLS: the certificate chain,
+    /// starting with the client's own certificate, and its private key.
+    pub fn client_cert_pem(
+        self,
+        cert_chain_pem: &[u8],
+        private_key_pem: &[u8],
+    ) -> Result<TlsConfig, Error> {
+        let chain = CertificateDer::pem_slice_iter(cert_chain_pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid("Invalid client certificate PEM"))?;
+        if chain.is_empty() {
+            return Err(invalid("No certificate in client certificate PEM"));
+        }
+        let key = PrivateKeyDer::from_pem_slice(private_key_pem)
+            .map_err(|_| invalid("Invalid client private key PEM"))?;
+
+        // Catch keys rustls can not use now rather than on the first connection
+        #[cfg(not(target_arch = "wasm32"))]
+        rustls::ClientConfig::builder()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_client_auth_cert(chain.clone(), key.clone_key())
+            .map_err(|err| invalid(&format!("Unusable client certificate: {}", err)))?;
+
+        Ok(TlsConfig {
+            client_cert: Some((chain, Arc::new(key))),
+            ..self
+        })
+    }
+
+    /// Accept any server certificate, only meant for testing against self-signed servers.
+    /// Overrides the root certificates and pins.
+    pub fn disable_verification(self, disable_verification: bool) -> TlsConfig {
+        TlsConfig {
+            disable_verification,
+            ..self
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use super::TlsConfig;
+    use rustls::client::WebPkiServerVerifier;
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+    use rustls::{DigitallySignedStruct, SignatureScheme};
+    use std::sync::Arc;
+
+    impl TlsConfig {
+        pub(crate) fn rustls_config(&self) -> Arc<rustls::ClientConfig> {
+            let verifier: Arc<dyn ServerCertVerifier> = if self.disable_verification {
+                Arc::new(NoCertificateVerification {})
+            } else {
+                let mut roots = rustls::RootCertStore {
+                    roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+                };
+                let (_, ignored) = roots.add_parsable_certificates(self.root_certs.iter().cloned());
+                if ignored != 0 {
+                    log::warn!("Ignored {} unusable root certificates", ignored);
+                }
+                let webpki = WebPkiServerVerifier::builder(Arc::new(roots))
+                    .build()
+                    .expect("web root certificates are never empty");
+
+                if self.pinned_keys.is_empty() {
+                    webpki
+                } else {
+                    Arc::new(PinnedKeyVerification {
+                        inner: webpki,
+                        pinned_keys: self.pinned_keys.clone(),
+                    })
+                }
+            };
+
+            let builder = rustls::ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(verifier);
+            let config = match &self.client_cert {
+                Some((chain, key)) => builder
+                    .with_client_auth_cert(chain.clone(), key.clone_key())
+                    .expect("client certificate checked by client_cert_pem"),
+                None => builder.with_no_client_auth(),
+            };
+            Arc::new(config)
+        }
+    }
+
+    /// The usual verification, then a check that a key of the chain is pinned.
+    #[derive(Debug)]
+    struct PinnedKeyVerification {
+        inner: Arc<WebPkiServerVerifier>,
+        pinned_keys: Vec<[u8; 32]>,
+    }
+
+    impl ServerCertVerifier for PinnedKeyVerification {
+        fn verify_server_cert(
+            &self,
+            end_entity: &CertificateDer<'_>,
+            intermediates: &[CertificateDer<'_>],
+            server_name: &ServerName<'_>,
+            ocsp_response: &[u8],
+            now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            let verified = self.inner.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                ocsp_response,
+                now,
+            )?;
+
+            let pinned = std::iter::once(end_entity)
+                .chain(intermediates)
+                .filter_map(|cert| webpki::EndEntityCert::try_from(cert).ok())
+                .any(|cert| {
+                    let spki = cert.subject_public_key_info();
+                    let hash = ring::digest::digest(&ring::digest::SHA256, spki.as_ref());
+                    self.pinned_keys
+                        .iter()
+                        .any(|pinned| pinned[..] == *hash.as_ref())
+                });
+            if pinned {
+                Ok(verified)
+            } else {
+                Err(rustls::Error::InvalidCertificate(
+                    rustls::CertificateError::ApplicationVerificationFailure,
+                ))
+            }
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            self.inner.verify_tls12_signature(message, cert, dss)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            self.inner.verify_tls13_signature(message, cert, dss)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.inner.supported_verify_schemes()
+        }
+    }
+
+    // Add this struct for rustls certificate verification disabling
+    #[derive(Debug)]
+    struct NoCertificateVerification;
+
+    impl ServerCertVerifier for NoCertificateVerification {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            // For this example, we will skip all verification
+            // In a real application, you should implement proper certificate verification
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            // For TLS 1.2, we can skip the verification
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            // For TLS 1.3, we can skip the verification
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            vec![
+                SignatureScheme::RSA_PKCS1_SHA256,
+                SignatureScheme::RSA_PKCS1_SHA384,
+                SignatureScheme::RSA_PKCS1_SHA512,
+                SignatureScheme::RSA_PSS_SHA256,
+                SignatureScheme::RSA_PSS_SHA384,
+                SignatureScheme::RSA_PSS_SHA512,
+                SignatureScheme::ECDSA_NISTP256_SHA256,
+                SignatureScheme::ECDSA_NISTP384_SHA384,
+                SignatureScheme::ECDSA_NISTP521_SHA512,
+                SignatureScheme::ED25519,
+                SignatureScheme::ED448,
+            ]
+        }
+    }
+}
@@ -5,8 +5,24 @@ pub(crate) mod js_web_socket {
     use crate::JsObject;
     use crate::error::Error;
     use crate::quad_socket::client::IncomingSocketMessage;
+    use std::task::{Context, Poll, Waker};
 
-    pub struct WebSocket;
+    pub struct WebSocket {
+        closed: bool,
+    }
+
+    thread_local! {
+        /// Task awaiting the next message of the socket.
+        static WAKER: std::cell::RefCell<Option<Waker>> = const { std::cell::RefCell::new(None) };
+    }
+
+    /// Called by JS when a message for the socket arrives.
+    #[unsafe(no_mangle)]
+    extern "C" fn quad_net_ws_wake() {
+        if let Some(waker) = WAKER.take() {
+            waker.wake();
+        }
+    }
 
     const CONNECTED: u32 = 0;
     const PACKED_RECEIVED: u32 = 1;
@@ -44,16 +60,31 @@ pub(crate) mod js_web_socket {
                             std::io::Error::new(std::io::ErrorKind::Other, json_error),
                         )))
                     }
-                    CLOSED => Some(IncomingSocketMessage::Closed),
+                    CLOSED => {
+                        self.closed = true;
+                        Some(IncomingSocketMessage::Closed)
+                    }
                     _ => None,
                 };
             }
             None
         }
 
+        /// `None` once the socket is closed and every message was received.
+        pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<IncomingSocketMessage>> {
+            match self.try_recv() {
+                Some(msg) => Poll::Ready(Some(msg)),
+                None if self.closed => Poll::Ready(None),
+                None => {
+                    WAKER.set(Some(cx.waker().clone()));
+                    Poll::Pending
+                }
+            }
+        }
+
         pub fn connect(addr: impl Into<String>) -> WebSocket {
             unsafe { ws_connect(JsObject::string(&addr.into())) };
-            WebSocket
+            WebSocket { closed: false }
         }
 
         pub fn close(&self) {